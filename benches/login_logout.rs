@@ -0,0 +1,131 @@
+//! Logs a crowd of clients in over websockets, then out again, and reports how many logins and logouts per
+//! second the server got through. By default 10k clients across 1k rooms, each client in 5 of them.
+//!
+//! Runs against a live server with mock auth (every login fetches the user's rooms, so the database is part of
+//! what gets measured):
+//!
+//! ```sh
+//! AUTH_MODE=mock MAX_UNAUTHENTICATED_SOCKETS_PER_IP=100000 cargo run --release -- serve --migrate
+//! ulimit -n 65536
+//! cargo bench --bench login_logout
+//! ```
+//!
+//! BENCH_CLIENTS, BENCH_ROOMS, BENCH_ROOMS_PER_CLIENT and BENCH_CONCURRENCY change the shape of the run.
+//! BENCH_CLIENTS must be a multiple of BENCH_ROOMS.
+
+use std::time::{Duration, Instant};
+
+use futures::{stream, StreamExt};
+use rand::Rng;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn base_url() -> String {
+    std::env::var("CHAT_BACKEND_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+fn env_or(key: &str, default: usize) -> usize {
+    std::env::var(key)
+        .ok()
+        .map(|value| value.parse().unwrap_or_else(|_| panic!("{key} must be a number")))
+        .unwrap_or(default)
+}
+
+/// Client `n` is user `first_user_id + n`. Each room gets `clients / rooms` consecutive users from each of
+/// `rooms_per_client` evenly spaced blocks, so every client ends up in exactly `rooms_per_client` rooms.
+fn room_members(room: usize, clients: usize, rooms: usize, rooms_per_client: usize, first_user_id: u32) -> Vec<u32> {
+    let users_per_block = clients / rooms;
+    (0..rooms_per_client)
+        .flat_map(|block| {
+            (0..users_per_block).map(move |user| (room * users_per_block + user + block * clients / rooms_per_client) % clients)
+        })
+        .map(|client| first_user_id + client as u32)
+        .collect()
+}
+
+async fn create_rooms(clients: usize, rooms: usize, rooms_per_client: usize, concurrency: usize, first_user_id: u32) {
+    let client = reqwest::Client::new();
+    stream::iter(0..rooms)
+        .for_each_concurrent(concurrency, |room| {
+            let client = client.clone();
+            async move {
+                let members = room_members(room, clients, rooms, rooms_per_client, first_user_id);
+                let owner_id = members[0];
+                let response = client
+                    .post(format!("{}/chat/room/bench-{room}", base_url()))
+                    .bearer_auth(format!("{owner_id}:mock-{owner_id}"))
+                    .json(&json!({ "participants": members }))
+                    .send()
+                    .await
+                    .expect("Couldn't reach the server, is it running?");
+                assert_eq!(response.status(), StatusCode::OK, "couldn't create room {room}");
+            }
+        })
+        .await;
+}
+
+/// Opens a socket with the credentials in the query and waits until the server says it's logged in.
+async fn log_in(user_id: u32) -> Socket {
+    let url = format!(
+        "{}/websocket?user_id={user_id}&token=mock-{user_id}",
+        base_url().replacen("http", "ws", 1)
+    );
+    let (mut socket, _) = connect_async(url).await.expect("Couldn't open a socket");
+    loop {
+        let message = timeout(Duration::from_secs(30), socket.next())
+            .await
+            .expect("Timed out waiting to log in")
+            .expect("Socket closed before logging in")
+            .expect("Socket error");
+        if let Message::Text(text) = message {
+            let frame: Value = serde_json::from_str(&text).unwrap_or_default();
+            if frame["head"] == "LOGGED IN" {
+                return socket;
+            }
+        }
+    }
+}
+
+/// Sends a close frame and waits for the server to close its side, which happens after it cleaned up the session.
+async fn log_out(mut socket: Socket) {
+    socket.close(None).await.expect("Couldn't close the socket");
+    while let Ok(Some(Ok(_))) = timeout(Duration::from_secs(30), socket.next()).await {}
+}
+
+fn report(action: &str, count: usize, elapsed: Duration) {
+    println!(
+        "{action}: {count} in {:.2}s, {:.0}/s",
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let clients = env_or("BENCH_CLIENTS", 10_000);
+    let rooms = env_or("BENCH_ROOMS", 1_000);
+    let rooms_per_client = env_or("BENCH_ROOMS_PER_CLIENT", 5);
+    let concurrency = env_or("BENCH_CONCURRENCY", 500);
+    assert_eq!(clients % rooms, 0, "BENCH_CLIENTS must be a multiple of BENCH_ROOMS");
+    // Fresh users every run so old rooms don't change the numbers.
+    let first_user_id = rand::thread_rng().gen_range(1_000_000..u32::MAX - clients as u32);
+
+    println!("Creating {rooms} rooms for {clients} clients, {rooms_per_client} rooms each...");
+    create_rooms(clients, rooms, rooms_per_client, concurrency, first_user_id).await;
+
+    let started = Instant::now();
+    let sockets: Vec<Socket> = stream::iter(0..clients as u32)
+        .map(|client| log_in(first_user_id + client))
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    report("Logins", sockets.len(), started.elapsed());
+
+    let started = Instant::now();
+    stream::iter(sockets).for_each_concurrent(concurrency, log_out).await;
+    report("Logouts", clients, started.elapsed());
+}
@@ -0,0 +1,115 @@
+-- Constraints and indexes the first migrations were missing.
+-- MySQL commits every ALTER on its own, so if this fails halfway the steps that already ran stay. Every step checks
+-- whether it's already been done so the migration can be run again after fixing whatever made it fail. MySQL has no
+-- IF NOT EXISTS for keys, indexes, columns or constraints, those steps look in information_schema and run a no-op
+-- instead if they're already there.
+
+-- Rows that point to rooms that don't exist anymore would make the foreign keys fail. They get moved to
+-- orphaned_chat_users and orphaned_message instead of being dropped, drop those by hand once nobody needs them.
+CREATE TABLE IF NOT EXISTS orphaned_chat_users LIKE chat_users;
+INSERT INTO orphaned_chat_users
+    SELECT cu.* FROM chat_users cu LEFT JOIN chat_room cr ON cr.id = cu.chat_room_id
+    WHERE cr.id IS NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM orphaned_chat_users o
+            WHERE o.chat_room_id = cu.chat_room_id AND o.user_id = cu.user_id AND o.time_joined = cu.time_joined
+        );
+DELETE cu FROM chat_users cu LEFT JOIN chat_room cr ON cr.id = cu.chat_room_id WHERE cr.id IS NULL;
+CREATE TABLE IF NOT EXISTS orphaned_message LIKE message;
+-- orphaned_message has message's primary key, messages archived by an earlier run get skipped.
+INSERT IGNORE INTO orphaned_message
+    SELECT m.* FROM message m LEFT JOIN chat_room cr ON cr.id = m.to_id WHERE cr.id IS NULL;
+DELETE m FROM message m LEFT JOIN chat_room cr ON cr.id = m.to_id WHERE cr.id IS NULL;
+
+-- A user could be added twice to the same room, keep the first time they joined. Rows can be exact copies of
+-- each other, dedup_id tells them apart just for this. Once the primary key is there there's nothing to dedupe.
+SET @chat_users_has_primary_key = (
+    SELECT COUNT(*) > 0 FROM information_schema.statistics
+    WHERE table_schema = DATABASE() AND table_name = 'chat_users' AND index_name = 'PRIMARY'
+);
+SET @chat_users_has_dedup_id = (
+    SELECT COUNT(*) > 0 FROM information_schema.columns
+    WHERE table_schema = DATABASE() AND table_name = 'chat_users' AND column_name = 'dedup_id'
+);
+SET @step = IF(
+    @chat_users_has_primary_key OR @chat_users_has_dedup_id,
+    'DO 0',
+    'ALTER TABLE chat_users ADD COLUMN dedup_id INT UNSIGNED NOT NULL AUTO_INCREMENT, ADD UNIQUE KEY (dedup_id)'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+SET @step = IF(
+    @chat_users_has_primary_key,
+    'DO 0',
+    'DELETE newer FROM chat_users newer
+        JOIN chat_users older
+            ON older.chat_room_id = newer.chat_room_id
+            AND older.user_id = newer.user_id
+            AND (older.time_joined < newer.time_joined
+                OR (older.time_joined = newer.time_joined AND older.dedup_id < newer.dedup_id))'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+SET @step = IF(
+    (SELECT COUNT(*) > 0 FROM information_schema.columns
+        WHERE table_schema = DATABASE() AND table_name = 'chat_users' AND column_name = 'dedup_id'),
+    'ALTER TABLE chat_users DROP COLUMN dedup_id',
+    'DO 0'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+
+-- The primary key covers get_all_in_chat_room.sql and remove_participant.sql,
+-- the user_id index covers fetch_all_user_is_in.sql (including its ORDER BY).
+-- Deleting a room takes its participants and messages with it.
+SET @step = IF(
+    @chat_users_has_primary_key,
+    'DO 0',
+    'ALTER TABLE chat_users ADD PRIMARY KEY (chat_room_id, user_id)'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+SET @step = IF(
+    (SELECT COUNT(*) > 0 FROM information_schema.statistics
+        WHERE table_schema = DATABASE() AND table_name = 'chat_users' AND index_name = 'chat_users_user_id_time_joined'),
+    'DO 0',
+    'CREATE INDEX chat_users_user_id_time_joined ON chat_users (user_id, time_joined)'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+SET @step = IF(
+    (SELECT COUNT(*) > 0 FROM information_schema.table_constraints
+        WHERE constraint_schema = DATABASE() AND table_name = 'chat_users' AND constraint_name = 'chat_users_chat_room_fk'),
+    'DO 0',
+    'ALTER TABLE chat_users
+        ADD CONSTRAINT chat_users_chat_room_fk FOREIGN KEY (chat_room_id) REFERENCES chat_room (id) ON DELETE CASCADE'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+
+-- Messages get fetched per room, newest first.
+SET @step = IF(
+    (SELECT COUNT(*) > 0 FROM information_schema.statistics
+        WHERE table_schema = DATABASE() AND table_name = 'message' AND index_name = 'message_to_id_id'),
+    'DO 0',
+    'CREATE INDEX message_to_id_id ON message (to_id, id)'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
+SET @step = IF(
+    (SELECT COUNT(*) > 0 FROM information_schema.table_constraints
+        WHERE constraint_schema = DATABASE() AND table_name = 'message' AND constraint_name = 'message_chat_room_fk'),
+    'DO 0',
+    'ALTER TABLE message
+        ADD CONSTRAINT message_chat_room_fk FOREIGN KEY (to_id) REFERENCES chat_room (id) ON DELETE CASCADE'
+);
+PREPARE step FROM @step;
+EXECUTE step;
+DEALLOCATE PREPARE step;
@@ -0,0 +1,12 @@
+-- Every kick gets recorded. While banned_until is in the future the user can't be added back to the room.
+CREATE TABLE IF NOT EXISTS chat_room_kick (
+    id INT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+    chat_room_id INT UNSIGNED NOT NULL,
+    user_id INT UNSIGNED NOT NULL,
+    kicked_by INT UNSIGNED NOT NULL,
+    reason VARCHAR(255) NULL,
+    time_kicked TIMESTAMP NOT NULL,
+    banned_until TIMESTAMP NULL,
+    INDEX chat_room_kick_room_banned_until (chat_room_id, banned_until),
+    CONSTRAINT chat_room_kick_chat_room_fk FOREIGN KEY (chat_room_id) REFERENCES chat_room (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+DELETE FROM chat_room
+WHERE id = ?;
\ No newline at end of file
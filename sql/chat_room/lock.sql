@@ -0,0 +1,3 @@
+SELECT owner_id FROM chat_room
+WHERE id = ?
+FOR UPDATE;
\ No newline at end of file
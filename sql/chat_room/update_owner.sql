@@ -0,0 +1,4 @@
+UPDATE chat_room SET
+owner_id = ?,
+last_updated = ?
+WHERE id = ?;
\ No newline at end of file
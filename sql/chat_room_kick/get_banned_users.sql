@@ -0,0 +1,2 @@
+SELECT DISTINCT user_id FROM chat_room_kick
+WHERE chat_room_id = ? AND banned_until > ?
\ No newline at end of file
@@ -0,0 +1,17 @@
+INSERT INTO chat_room_kick (
+    id,
+    chat_room_id,
+    user_id,
+    kicked_by,
+    reason,
+    time_kicked,
+    banned_until
+) VALUES (
+    NULL,
+    ?,
+    ?,
+    ?,
+    ?,
+    ?,
+    ?
+)
\ No newline at end of file
@@ -0,0 +1,146 @@
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use tracing::info;
+
+use crate::{
+    config::Config, dao::main_dao, net::metrics::install_recorder, routes::http::main_router::start_server,
+    service::{auth::authenticator_from_config, fanout::fanout_from_config},
+};
+
+/// Exit codes, so deploy scripts can tell what went wrong without parsing the output.
+pub const EXIT_SERVER_ERROR: u8 = 1;
+pub const EXIT_INVALID_CONFIG: u8 = 2;
+pub const EXIT_DATABASE_UNAVAILABLE: u8 = 3;
+pub const EXIT_MIGRATIONS_FAILED: u8 = 4;
+pub const EXIT_AUTH_SETUP_FAILED: u8 = 5;
+pub const EXIT_FANOUT_SETUP_FAILED: u8 = 6;
+
+#[derive(Debug, Parser)]
+#[command(about = "Chat backend: websockets and REST routes for chat rooms.")]
+pub struct Cli {
+    /// Defaults to serve.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, Subcommand)]
+pub enum Command {
+    /// Runs the server.
+    Serve {
+        /// Apply pending migrations before serving. Same as setting database.auto_migrate.
+        #[arg(long)]
+        migrate: bool,
+    },
+    /// Applies every pending migration in ./migrations.
+    Migrate {
+        /// Only list the pending migrations, don't touch the database.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Checks the config, the database connection, that no migrations are pending, the auth setup and the fanout, then exits.
+    Check,
+}
+
+impl Default for Command {
+    fn default() -> Self {
+        Command::Serve { migrate: false }
+    }
+}
+
+pub async fn run(command: Command, config: Config) -> Result<(), ExitCode> {
+    match command {
+        Command::Serve { migrate } => serve(config, migrate).await,
+        Command::Migrate { dry_run } => migrate(&config, dry_run).await,
+        Command::Check => check(&config).await,
+    }
+}
+
+async fn serve(config: Config, migrate: bool) -> Result<(), ExitCode> {
+    let database_pool = connect(&config).await?;
+    if migrate || config.database.auto_migrate {
+        if let Err(error) = main_dao::run_all_migrations(&database_pool).await {
+            return Err(fail(EXIT_MIGRATIONS_FAILED, format!("Couldn't run migrations: {error}")));
+        }
+        info!("Successfully ran migrations");
+    }
+    // Checked once here instead of on every readiness probe, migrations don't get applied behind a running server.
+    require_no_pending_migrations(&database_pool).await?;
+    let client_pool = reqwest::Client::new();
+    let authenticator = match authenticator_from_config(&config.auth, client_pool.clone()).await {
+        Ok(authenticator) => authenticator,
+        Err(error) => return Err(fail(EXIT_AUTH_SETUP_FAILED, error)),
+    };
+    let fanout = match fanout_from_config(&config.fanout).await {
+        Ok(fanout) => fanout,
+        Err(error) => return Err(fail(EXIT_FANOUT_SETUP_FAILED, error)),
+    };
+    let prometheus = match install_recorder() {
+        Ok(prometheus) => prometheus,
+        Err(error) => return Err(fail(EXIT_SERVER_ERROR, format!("Couldn't set up metrics: {error}"))),
+    };
+    match start_server(&config, database_pool, client_pool, authenticator, fanout, prometheus).await {
+        Ok(()) => Ok(()),
+        Err(error) => Err(fail(EXIT_SERVER_ERROR, format!("Server stopped with an error: {error}"))),
+    }
+}
+
+async fn migrate(config: &Config, dry_run: bool) -> Result<(), ExitCode> {
+    let database_pool = connect(config).await?;
+    let pending_migrations = match main_dao::pending_migrations(&database_pool).await {
+        Ok(pending_migrations) => pending_migrations,
+        Err(error) => return Err(fail(EXIT_MIGRATIONS_FAILED, format!("Couldn't list migrations: {error}"))),
+    };
+    if pending_migrations.is_empty() {
+        println!("No pending migrations.");
+        return Ok(());
+    }
+    for migration in &pending_migrations {
+        println!("Pending: {} {}", migration.version, migration.description);
+    }
+    if dry_run {
+        return Ok(());
+    }
+    match main_dao::run_all_migrations(&database_pool).await {
+        Ok(()) => {
+            println!("Successfully ran {} migrations.", pending_migrations.len());
+            Ok(())
+        }
+        Err(error) => Err(fail(EXIT_MIGRATIONS_FAILED, format!("Couldn't run migrations: {error}"))),
+    }
+}
+
+async fn check(config: &Config) -> Result<(), ExitCode> {
+    let database_pool = connect(config).await?;
+    require_no_pending_migrations(&database_pool).await?;
+    if let Err(error) = authenticator_from_config(&config.auth, reqwest::Client::new()).await {
+        return Err(fail(EXIT_AUTH_SETUP_FAILED, error));
+    }
+    if let Err(error) = fanout_from_config(&config.fanout).await {
+        return Err(fail(EXIT_FANOUT_SETUP_FAILED, error));
+    }
+    println!("Everything looks good.");
+    Ok(())
+}
+
+async fn require_no_pending_migrations(database_pool: &sqlx::MySqlPool) -> Result<(), ExitCode> {
+    match main_dao::pending_migrations(database_pool).await {
+        Ok(pending_migrations) if pending_migrations.is_empty() => Ok(()),
+        Ok(pending_migrations) => Err(fail(
+            EXIT_MIGRATIONS_FAILED,
+            format!("{} migrations are pending, run the migrate subcommand.", pending_migrations.len()),
+        )),
+        Err(error) => Err(fail(EXIT_MIGRATIONS_FAILED, format!("Couldn't list migrations: {error}"))),
+    }
+}
+
+async fn connect(config: &Config) -> Result<sqlx::MySqlPool, ExitCode> {
+    main_dao::start_database_connection(&config.database)
+        .await
+        .map_err(|error| fail(EXIT_DATABASE_UNAVAILABLE, format!("Couldn't connect to the database: {error}")))
+}
+
+fn fail(code: u8, message: String) -> ExitCode {
+    eprintln!("{message}");
+    ExitCode::from(code)
+}
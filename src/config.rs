@@ -0,0 +1,619 @@
+use std::{fmt::Display, fs, net::SocketAddr, path::Path, str::FromStr};
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::util::{
+    env::{override_from_env, override_list_from_env, override_option_from_env},
+    rate_limit::RateLimit,
+};
+
+/// Everything that can be configured. Gets loaded once on startup, from the TOML file at CONFIG_FILE (if set)
+/// and then from env vars, which override whatever the file says. See `chat.example.toml` for every key.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub websocket: WebSocketConfig,
+    pub limits: LimitsConfig,
+    pub rooms: RoomsConfig,
+    pub fanout: FanoutConfig,
+    pub rate_limits: RateLimitsConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    /// How long to wait on sockets and background tasks when shutting down.
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+    /// Apply pending migrations every time the server starts.
+    pub auto_migrate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    Remote,
+    Jwt,
+    Mock,
+}
+
+/// What happens when the owner of a room leaves it while there are other participants left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnerLeavePolicy {
+    /// The owner can't leave until they're the last participant.
+    Block,
+    /// The participant that's been in the room the longest becomes the owner.
+    Transfer,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    /// The user-svc endpoint that validates tokens. If not set, dev-communicators' default is used.
+    pub user_svc_url: Option<String>,
+    /// How long tokens validated by user-svc get cached, 0 disables the cache.
+    pub cache_ttl_secs: u64,
+    pub jwt_secret: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    /// Bearer token for the admin endpoints, they're disabled if it's not set.
+    pub admin_token: Option<String>,
+    /// Users that can see and manage every room, whether they're in it or not.
+    pub admin_user_ids: Vec<u32>,
+    /// If true, sockets have to send their credentials while opening the connection.
+    pub require_handshake_auth: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub heartbeat_interval_secs: u64,
+    pub heartbeat_timeout_secs: u64,
+    pub login_timeout_secs: u64,
+    pub session_revalidation_secs: u64,
+    /// How many frames can be waiting to be written to a socket before the client counts as a slow consumer.
+    pub outbound_queue_capacity: usize,
+    /// How long writing a single frame to a socket can take before the client counts as a slow consumer.
+    pub send_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_login_attempts: u32,
+    pub max_unauthenticated_sockets_per_ip: usize,
+    /// How many messages a chat room's broadcast channel holds before slow receivers start missing them.
+    pub room_channel_capacity: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsConfig {
+    /// Most participants a room can have, owner included.
+    pub max_size: usize,
+    /// If set, every user id added to a room gets looked up here first (`{user_id}` gets replaced with the id).
+    /// A 404 means the user doesn't exist.
+    pub participant_lookup_url: Option<String>,
+    pub owner_leave_policy: OwnerLeavePolicy,
+    /// Delete rooms (messages and kicks included) when their last participant leaves.
+    pub delete_empty_rooms: bool,
+}
+
+/// Every limit is a `"<per_second>/<burst>"` token bucket.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitsConfig {
+    /// Messages each user can send, across all their rooms and sockets.
+    pub send_message: RateLimit,
+    /// Messages each room can get, from all its participants together.
+    pub send_message_per_room: RateLimit,
+    /// SeeMessages frames each user can send.
+    pub see_messages: RateLimit,
+    /// Requests each user can make to the chat room routes.
+    pub http: RateLimit,
+    /// Sockets that get rate limited this many times within a minute get disconnected.
+    pub max_violations_per_minute: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per event.
+    Text,
+    /// One JSON object per event, span fields included.
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Filter directives, a level (`info`) or per module levels (`info,chat_backend::dao=debug,sqlx=warn`).
+    pub level: String,
+    pub format: LogFormat,
+    /// Log the text of every frame sockets send. Off by default, it's the users' messages.
+    pub log_message_bodies: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FanoutMode {
+    Local,
+    Redis,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanoutConfig {
+    /// `local` only reaches sockets on this instance, `redis` reaches sockets on every instance using the same Redis.
+    pub mode: FanoutMode,
+    pub redis_url: Option<String>,
+    /// Every room gets published on `<channel_prefix><room id>`.
+    pub channel_prefix: String,
+    /// Session events (kicks, revocations...) get published on `<session_channel_prefix><user id>`.
+    pub session_channel_prefix: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            auto_migrate: false,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: AuthMode::Remote,
+            user_svc_url: None,
+            cache_ttl_secs: 30,
+            jwt_secret: None,
+            jwks_url: None,
+            jwt_issuer: None,
+            jwt_audience: None,
+            admin_token: None,
+            admin_user_ids: Vec::new(),
+            require_handshake_auth: false,
+        }
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: 30,
+            heartbeat_timeout_secs: 75,
+            login_timeout_secs: 10,
+            session_revalidation_secs: 300,
+            outbound_queue_capacity: 256,
+            send_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_login_attempts: 3,
+            max_unauthenticated_sockets_per_ip: 10,
+            room_channel_capacity: 150,
+        }
+    }
+}
+
+impl Default for RoomsConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 256,
+            participant_lookup_url: None,
+            owner_leave_policy: OwnerLeavePolicy::Transfer,
+            delete_empty_rooms: true,
+        }
+    }
+}
+
+impl Default for FanoutConfig {
+    fn default() -> Self {
+        Self {
+            mode: FanoutMode::Local,
+            redis_url: None,
+            channel_prefix: "chat:room:".to_string(),
+            session_channel_prefix: "chat:session:".to_string(),
+        }
+    }
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            send_message: RateLimit::new(5.0, 10),
+            send_message_per_room: RateLimit::new(20.0, 40),
+            see_messages: RateLimit::new(10.0, 20),
+            http: RateLimit::new(5.0, 20),
+            max_violations_per_minute: 10,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            log_message_bodies: false,
+        }
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "remote" => Ok(AuthMode::Remote),
+            "jwt" => Ok(AuthMode::Jwt),
+            "mock" => Ok(AuthMode::Mock),
+            other => Err(format!("unknown auth mode {other}, must be remote, jwt or mock")),
+        }
+    }
+}
+
+impl FromStr for FanoutMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "local" => Ok(FanoutMode::Local),
+            "redis" => Ok(FanoutMode::Redis),
+            other => Err(format!("unknown fanout mode {other}, must be local or redis")),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {other}, must be text or json")),
+        }
+    }
+}
+
+impl FromStr for OwnerLeavePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "block" => Ok(OwnerLeavePolicy::Block),
+            "transfer" => Ok(OwnerLeavePolicy::Transfer),
+            other => Err(format!("unknown owner leave policy {other}, must be block or transfer")),
+        }
+    }
+}
+
+/// Everything that's wrong with the config, all at once so it doesn't take five restarts to fix it.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("CONFIG_FILE") {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) => Self::default(),
+        };
+        let mut errors = config.apply_env();
+        errors.extend(config.validate());
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(errors)),
+        }
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|error| ConfigError(vec![format!("couldn't read {}: {error}", path.display())]))?;
+        toml::from_str(&contents)
+            .map_err(|error| ConfigError(vec![format!("couldn't parse {}: {error}", path.display())]))
+    }
+
+    /// Env vars keep the names they had before the config file existed.
+    fn apply_env(&mut self) -> Vec<String> {
+        let results = [
+            override_from_env("LISTEN_ADDR", &mut self.server.listen_addr),
+            override_from_env("SHUTDOWN_TIMEOUT_SECS", &mut self.server.shutdown_timeout_secs),
+            override_from_env("DATABASE_URL", &mut self.database.url),
+            override_from_env("DATABASE_MAX_CONNECTIONS", &mut self.database.max_connections),
+            override_from_env("DATABASE_MIN_CONNECTIONS", &mut self.database.min_connections),
+            override_from_env("DATABASE_ACQUIRE_TIMEOUT_SECS", &mut self.database.acquire_timeout_secs),
+            override_from_env("AUTO_MIGRATE", &mut self.database.auto_migrate),
+            override_from_env("AUTH_MODE", &mut self.auth.mode),
+            override_option_from_env("USER_SVC_URL", &mut self.auth.user_svc_url),
+            override_from_env("AUTH_CACHE_TTL_SECS", &mut self.auth.cache_ttl_secs),
+            override_option_from_env("JWT_SECRET", &mut self.auth.jwt_secret),
+            override_option_from_env("JWKS_URL", &mut self.auth.jwks_url),
+            override_option_from_env("JWT_ISSUER", &mut self.auth.jwt_issuer),
+            override_option_from_env("JWT_AUDIENCE", &mut self.auth.jwt_audience),
+            override_option_from_env("ADMIN_TOKEN", &mut self.auth.admin_token),
+            override_list_from_env("ADMIN_USER_IDS", &mut self.auth.admin_user_ids),
+            override_from_env("REQUIRE_HANDSHAKE_AUTH", &mut self.auth.require_handshake_auth),
+            override_from_env("HEARTBEAT_INTERVAL_SECS", &mut self.websocket.heartbeat_interval_secs),
+            override_from_env("HEARTBEAT_TIMEOUT_SECS", &mut self.websocket.heartbeat_timeout_secs),
+            override_from_env("LOGIN_TIMEOUT_SECS", &mut self.websocket.login_timeout_secs),
+            override_from_env("SESSION_REVALIDATION_SECS", &mut self.websocket.session_revalidation_secs),
+            override_from_env("OUTBOUND_QUEUE_CAPACITY", &mut self.websocket.outbound_queue_capacity),
+            override_from_env("SEND_TIMEOUT_SECS", &mut self.websocket.send_timeout_secs),
+            override_from_env("MAX_LOGIN_ATTEMPTS", &mut self.limits.max_login_attempts),
+            override_from_env(
+                "MAX_UNAUTHENTICATED_SOCKETS_PER_IP",
+                &mut self.limits.max_unauthenticated_sockets_per_ip,
+            ),
+            override_from_env("ROOM_CHANNEL_CAPACITY", &mut self.limits.room_channel_capacity),
+            override_from_env("MAX_ROOM_SIZE", &mut self.rooms.max_size),
+            override_option_from_env("PARTICIPANT_LOOKUP_URL", &mut self.rooms.participant_lookup_url),
+            override_from_env("OWNER_LEAVE_POLICY", &mut self.rooms.owner_leave_policy),
+            override_from_env("DELETE_EMPTY_ROOMS", &mut self.rooms.delete_empty_rooms),
+            override_from_env("FANOUT_MODE", &mut self.fanout.mode),
+            override_option_from_env("REDIS_URL", &mut self.fanout.redis_url),
+            override_from_env("REDIS_CHANNEL_PREFIX", &mut self.fanout.channel_prefix),
+            override_from_env("REDIS_SESSION_CHANNEL_PREFIX", &mut self.fanout.session_channel_prefix),
+            override_from_env("SEND_MESSAGE_RATE_LIMIT", &mut self.rate_limits.send_message),
+            override_from_env("ROOM_SEND_MESSAGE_RATE_LIMIT", &mut self.rate_limits.send_message_per_room),
+            override_from_env("SEE_MESSAGES_RATE_LIMIT", &mut self.rate_limits.see_messages),
+            override_from_env("HTTP_RATE_LIMIT", &mut self.rate_limits.http),
+            override_from_env("MAX_RATE_LIMIT_VIOLATIONS_PER_MINUTE", &mut self.rate_limits.max_violations_per_minute),
+            override_from_env("LOG_LEVEL", &mut self.logging.level),
+            override_from_env("LOG_FORMAT", &mut self.logging.format),
+            override_from_env("LOG_MESSAGE_BODIES", &mut self.logging.log_message_bodies),
+        ];
+        results.into_iter().filter_map(Result::err).collect()
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.server.shutdown_timeout_secs == 0 {
+            errors.push("server.shutdown_timeout_secs (SHUTDOWN_TIMEOUT_SECS) must be at least 1".to_string());
+        }
+        if self.database.url.is_empty() {
+            errors.push("database.url (DATABASE_URL) is required".to_string());
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be at least 1".to_string());
+        }
+        if self.database.min_connections > self.database.max_connections {
+            errors.push("database.min_connections can't be more than database.max_connections".to_string());
+        }
+        if self.auth.mode == AuthMode::Jwt && self.auth.jwt_secret.is_none() && self.auth.jwks_url.is_none() {
+            errors.push("auth.mode = jwt needs either auth.jwt_secret (JWT_SECRET) or auth.jwks_url (JWKS_URL)".to_string());
+        }
+        if self.auth.admin_token.as_deref() == Some("") {
+            errors.push("auth.admin_token can't be empty, leave it out to disable the admin endpoints".to_string());
+        }
+        if self.websocket.heartbeat_interval_secs == 0 {
+            errors.push("websocket.heartbeat_interval_secs must be at least 1".to_string());
+        }
+        if self.websocket.heartbeat_timeout_secs <= self.websocket.heartbeat_interval_secs {
+            errors.push("websocket.heartbeat_timeout_secs must be longer than websocket.heartbeat_interval_secs".to_string());
+        }
+        if self.websocket.login_timeout_secs == 0 {
+            errors.push("websocket.login_timeout_secs must be at least 1".to_string());
+        }
+        if self.websocket.session_revalidation_secs == 0 {
+            errors.push("websocket.session_revalidation_secs must be at least 1".to_string());
+        }
+        if self.websocket.outbound_queue_capacity == 0 {
+            errors.push("websocket.outbound_queue_capacity must be at least 1".to_string());
+        }
+        if self.websocket.send_timeout_secs == 0 {
+            errors.push("websocket.send_timeout_secs must be at least 1".to_string());
+        }
+        if self.limits.max_login_attempts == 0 {
+            errors.push("limits.max_login_attempts must be at least 1".to_string());
+        }
+        if self.limits.room_channel_capacity == 0 {
+            errors.push("limits.room_channel_capacity must be at least 1".to_string());
+        }
+        if self.rooms.max_size < 2 {
+            errors.push("rooms.max_size must be at least 2".to_string());
+        }
+        if let Some(participant_lookup_url) = &self.rooms.participant_lookup_url {
+            if !participant_lookup_url.contains("{user_id}") {
+                errors.push("rooms.participant_lookup_url must contain {user_id}".to_string());
+            }
+        }
+        if self.rate_limits.max_violations_per_minute == 0 {
+            errors.push("rate_limits.max_violations_per_minute must be at least 1".to_string());
+        }
+        if self.fanout.mode == FanoutMode::Redis && self.fanout.redis_url.is_none() {
+            errors.push("fanout.mode = redis needs fanout.redis_url (REDIS_URL)".to_string());
+        }
+        // Each prefix gets its own pattern subscription, a channel matching both would be delivered twice.
+        let (channel_prefix, session_channel_prefix) = (&self.fanout.channel_prefix, &self.fanout.session_channel_prefix);
+        if self.fanout.mode == FanoutMode::Redis
+            && (channel_prefix.starts_with(session_channel_prefix.as_str()) || session_channel_prefix.starts_with(channel_prefix.as_str()))
+        {
+            errors.push("fanout.channel_prefix and fanout.session_channel_prefix can't be prefixes of each other".to_string());
+        }
+        if let Err(error) = EnvFilter::try_new(&self.logging.level) {
+            errors.push(format!("logging.level (LOG_LEVEL) isn't a valid filter: {error}"));
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, net::SocketAddr, path::PathBuf, sync::Mutex};
+
+    use super::{AuthMode, Config, FanoutMode};
+    use crate::util::rate_limit::RateLimit;
+
+    /// Env vars are shared by the whole process, tests that set them or read them take turns. None of them touch
+    /// DATABASE_URL, the database tests need it.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// A valid config as far as `validate` is concerned.
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.database.url = "mysql://localhost/chat".to_string();
+        config
+    }
+
+    fn write_config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("chat-config-{name}-{}.toml", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults_only_need_a_database_url() {
+        let config = Config::default();
+        assert_eq!(config.server.listen_addr, SocketAddr::from(([0, 0, 0, 0], 3000)));
+        assert_eq!(config.server.shutdown_timeout_secs, 30);
+        assert_eq!(config.auth.mode, AuthMode::Remote);
+        assert_eq!(config.auth.admin_token, None);
+        assert_eq!(config.fanout.mode, FanoutMode::Local);
+        assert_eq!(config.rate_limits.send_message, RateLimit::new(5.0, 10));
+        assert_eq!(config.validate(), vec!["database.url (DATABASE_URL) is required".to_string()]);
+        assert!(valid_config().validate().is_empty());
+    }
+
+    #[test]
+    fn env_vars_override_the_file() {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let path = write_config_file(
+            "precedence",
+            r#"
+            [server]
+            shutdown_timeout_secs = 5
+
+            [auth]
+            cache_ttl_secs = 60
+
+            [rooms]
+            max_size = 10
+            "#,
+        );
+        env::set_var("SHUTDOWN_TIMEOUT_SECS", "7");
+        env::set_var("AUTH_CACHE_TTL_SECS", "0");
+        env::remove_var("MAX_ROOM_SIZE");
+
+        let mut config = Config::from_file(&path).unwrap();
+        let errors = config.apply_env();
+        env::remove_var("SHUTDOWN_TIMEOUT_SECS");
+        env::remove_var("AUTH_CACHE_TTL_SECS");
+        fs::remove_file(&path).unwrap();
+
+        assert!(errors.is_empty(), "{errors:?}");
+        assert_eq!(config.server.shutdown_timeout_secs, 7);
+        assert_eq!(config.auth.cache_ttl_secs, 0);
+        // Not in the env, so the file wins over the default.
+        assert_eq!(config.rooms.max_size, 10);
+    }
+
+    #[test]
+    fn unknown_keys_in_the_file_are_rejected() {
+        let path = write_config_file("unknown-key", "[server]\nshutdown_timeout = 5\n");
+        let error = Config::from_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.0[0].contains("shutdown_timeout"), "{error}");
+    }
+
+    #[test]
+    fn bad_rate_limits_are_rejected() {
+        let path = write_config_file("bad-rate-limit", "[rate_limits]\nsend_message = \"fast\"\n");
+        let error = Config::from_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.0[0].contains("fast"), "{error}");
+
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        env::set_var("SEND_MESSAGE_RATE_LIMIT", "5");
+        let errors = valid_config().apply_env();
+        env::remove_var("SEND_MESSAGE_RATE_LIMIT");
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        assert!(errors[0].starts_with("SEND_MESSAGE_RATE_LIMIT"), "{errors:?}");
+    }
+
+    #[test]
+    fn shutdown_timeout_has_to_be_at_least_a_second() {
+        let mut config = valid_config();
+        config.server.shutdown_timeout_secs = 0;
+        assert_eq!(
+            config.validate(),
+            vec!["server.shutdown_timeout_secs (SHUTDOWN_TIMEOUT_SECS) must be at least 1".to_string()]
+        );
+    }
+
+    #[test]
+    fn admin_token_cant_be_empty() {
+        let mut config = valid_config();
+        config.auth.admin_token = Some(String::new());
+        assert_eq!(
+            config.validate(),
+            vec!["auth.admin_token can't be empty, leave it out to disable the admin endpoints".to_string()]
+        );
+    }
+
+    #[test]
+    fn redis_channel_prefixes_cant_overlap() {
+        let mut config = valid_config();
+        config.fanout.mode = FanoutMode::Redis;
+        config.fanout.redis_url = Some("redis://localhost".to_string());
+        assert!(config.validate().is_empty());
+
+        config.fanout.channel_prefix = "chat:".to_string();
+        config.fanout.session_channel_prefix = "chat:session:".to_string();
+        assert_eq!(
+            config.validate(),
+            vec!["fanout.channel_prefix and fanout.session_channel_prefix can't be prefixes of each other".to_string()]
+        );
+
+        // Only one pattern subscription with local fanout, nothing to overlap.
+        config.fanout.mode = FanoutMode::Local;
+        assert!(config.validate().is_empty());
+    }
+}
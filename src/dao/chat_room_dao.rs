@@ -1,12 +1,17 @@
 use chat_types::domain::{chat_room::ChatRoom, chat_user::ChatUser};
 use chrono::Utc;
-use sqlx::{mysql::MySqlQueryResult, MySqlPool};
+use sqlx::{mysql::MySqlQueryResult, Executor, MySql, MySqlPool};
+use tracing::instrument;
+
+use super::QueryTimer;
 
 #[allow(unused)]
-pub async fn insert_chat_room(
-    conn: &MySqlPool,
+#[instrument(level = "debug", skip_all)]
+pub async fn insert_chat_room<'c>(
+    conn: impl Executor<'c, Database = MySql>,
     chat_room: &ChatRoom,
 ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("insert_chat_room");
     match sqlx::query_file!(
         "sql/chat_room/insert.sql",
         chat_room.title,
@@ -23,10 +28,12 @@ pub async fn insert_chat_room(
 }
 
 #[allow(unused)]
+#[instrument(level = "debug", skip_all)]
 pub async fn get_chat_room_with_id(
     conn: &MySqlPool,
     chat_room_id: &u32,
 ) -> Result<Option<ChatRoom>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("get_chat_room_with_id");
     match sqlx::query_file_as!(ChatRoom, "sql/chat_room/get.sql", chat_room_id)
         .fetch_optional(conn)
         .await
@@ -37,10 +44,12 @@ pub async fn get_chat_room_with_id(
 }
 
 #[allow(unused)]
+#[instrument(level = "debug", skip_all)]
 pub async fn update_chat_room(
     conn: &MySqlPool,
     chat_room: &ChatRoom,
 ) -> Result<MySqlQueryResult, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("update_chat_room");
     match sqlx::query_file!(
         "sql/chat_room/update.sql",
         chat_room.title,
@@ -56,19 +65,59 @@ pub async fn update_chat_room(
     }
 }
 
-#[allow(unused)]
-pub async fn delete_chat_room(
-    conn: &MySqlPool,
+/// Locks the room's row until the transaction ends and returns its owner, so concurrent leaves don't both
+/// think someone else is still in the room.
+#[instrument(level = "debug", skip_all)]
+pub async fn lock_chat_room<'c>(
+    conn: impl Executor<'c, Database = MySql>,
+    chat_room_id: &u32,
+) -> Result<Option<u32>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("lock_chat_room");
+    match sqlx::query_file_scalar!("sql/chat_room/lock.sql", chat_room_id)
+        .fetch_optional(conn)
+        .await
+    {
+        Ok(owner_id) => Ok(owner_id),
+        Err(error) => Err(Box::new(error)),
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn update_chat_room_owner<'c>(
+    conn: impl Executor<'c, Database = MySql>,
+    chat_room_id: &u32,
+    owner_id: u32,
+) -> Result<MySqlQueryResult, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("update_chat_room_owner");
+    match sqlx::query_file!("sql/chat_room/update_owner.sql", owner_id, Utc::now(), chat_room_id)
+        .execute(conn)
+        .await
+    {
+        Ok(query_result) => Ok(query_result),
+        Err(error) => Err(Box::new(error)),
+    }
+}
+
+/// Participants, messages and kicks go with the room through the foreign keys.
+#[instrument(level = "debug", skip_all)]
+pub async fn delete_chat_room<'c>(
+    conn: impl Executor<'c, Database = MySql>,
     chat_room_id: &u32,
-) -> Result<ChatRoom, Box<dyn std::error::Error + Send + Sync>> {
-    Ok(ChatRoom::default())
+) -> Result<Option<()>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("delete_chat_room");
+    match sqlx::query_file!("sql/chat_room/delete.sql", chat_room_id).execute(conn).await {
+        Ok(query_result) => if query_result.rows_affected() > 0 { Ok(Some(())) } else { Ok(None) },
+        Err(error) => Err(Box::new(error)),
+    }
 }
 
 #[allow(unused)]
+#[instrument(level = "debug", skip_all)]
 pub async fn fetch_all_user_chat_rooms(
     conn: &MySqlPool,
     user_id: u32,
 ) -> Result<Vec<ChatRoom>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("fetch_all_user_chat_rooms");
     match sqlx::query_file_as!(ChatRoom, "sql/chat_room/fetch_all_user_is_in.sql", user_id)
         .fetch_all(conn)
         .await
@@ -78,11 +127,17 @@ pub async fn fetch_all_user_chat_rooms(
     }
 }
 
-pub async fn insert_chat_room_participants(
-    conn: &MySqlPool,
+#[instrument(level = "debug", skip_all)]
+pub async fn insert_chat_room_participants<'c>(
+    conn: impl Executor<'c, Database = MySql>,
     participant_ids: &Vec<u32>,
     chat_room_id: &u32
-) -> Result<MySqlQueryResult, Box<dyn std::error::Error>> {
+) -> Result<MySqlQueryResult, Box<dyn std::error::Error + Send + Sync>> {
+    // VALUES with nothing after it isn't valid SQL.
+    if participant_ids.is_empty() {
+        return Ok(MySqlQueryResult::default());
+    }
+    let _timer = QueryTimer::start("insert_chat_room_participants");
     let time = Utc::now();
     let mut query = "INSERT INTO chat_users (
     chat_room_id,
@@ -102,14 +157,18 @@ pub async fn insert_chat_room_participants(
     }
 }
 
-pub async fn get_chat_room_participants(conn: &MySqlPool, chat_room_id: &u32) -> Result<Vec<ChatUser>, Box<dyn std::error::Error>> {
+#[instrument(level = "debug", skip_all)]
+pub async fn get_chat_room_participants<'c>(conn: impl Executor<'c, Database = MySql>, chat_room_id: &u32) -> Result<Vec<ChatUser>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("get_chat_room_participants");
     match sqlx::query_file_as!(ChatUser, "sql/chat_users/get_all_in_chat_room.sql", chat_room_id).fetch_all(conn).await {
         Ok(chat_users) => Ok(chat_users),
         Err(error) => Err(Box::new(error)),
     }
 }
 
-pub async fn delete_chat_room_participant(conn: &MySqlPool, chat_room_id: &u32, user_id: u32) -> Result<Option<()>, Box<dyn std::error::Error>> {
+#[instrument(level = "debug", skip_all)]
+pub async fn delete_chat_room_participant<'c>(conn: impl Executor<'c, Database = MySql>, chat_room_id: &u32, user_id: u32) -> Result<Option<()>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("delete_chat_room_participant");
     match sqlx::query_file!("sql/chat_users/remove_participant.sql", chat_room_id, user_id).execute(conn).await {
         Ok(query_result) => if query_result.rows_affected() > 0 { return Ok(Some(())) } else {return Ok(None)},
         Err(error) => Err(Box::new(error)),
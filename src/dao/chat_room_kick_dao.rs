@@ -0,0 +1,45 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, MySql, MySqlPool};
+use tracing::instrument;
+
+use crate::{dao::QueryTimer, domain::chat_room_kick::ChatRoomKick};
+
+#[instrument(level = "debug", skip_all)]
+pub async fn insert_chat_room_kick<'c>(
+    conn: impl Executor<'c, Database = MySql>,
+    kick: &ChatRoomKick,
+) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("insert_chat_room_kick");
+    match sqlx::query_file!(
+        "sql/chat_room_kick/insert.sql",
+        kick.chat_room_id,
+        kick.user_id,
+        kick.kicked_by,
+        kick.reason,
+        kick.time_kicked,
+        kick.banned_until
+    )
+    .execute(conn)
+    .await
+    {
+        Ok(query_result) => Ok(query_result.last_insert_id()),
+        Err(error) => Err(Box::new(error)),
+    }
+}
+
+/// Users whose ban from the chat room hasn't run out yet.
+#[instrument(level = "debug", skip_all)]
+pub async fn get_banned_users(
+    conn: &MySqlPool,
+    chat_room_id: &u32,
+    now: DateTime<Utc>,
+) -> Result<Vec<u32>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("get_banned_users");
+    match sqlx::query_file_scalar!("sql/chat_room_kick/get_banned_users.sql", chat_room_id, now)
+        .fetch_all(conn)
+        .await
+    {
+        Ok(banned_users) => Ok(banned_users),
+        Err(error) => Err(Box::new(error)),
+    }
+}
@@ -1,25 +1,57 @@
-use std::{collections::HashMap, env};
+use std::time::Duration;
 
-use sqlx::MySqlPool;
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migration, Migrator},
+    mysql::MySqlPoolOptions,
+    MySqlPool,
+};
+use tracing::instrument;
 
-#[allow(dead_code)]
-pub async fn start_database_connection() -> Result<MySqlPool, sqlx::Error> {
-    let vars = env::vars().into_iter().collect::<HashMap<String, String>>();
-    let db_url = match vars.get("DATABASE_URL") {
-        Some(str) => str,
-        None => panic!("DATABASE_URL env var not found"),
-    };
-    let formatted_db_url = &db_url;
-    sqlx::MySqlPool::connect(formatted_db_url).await
+use crate::config::DatabaseConfig;
+
+pub async fn start_database_connection(config: &DatabaseConfig) -> Result<MySqlPool, sqlx::Error> {
+    MySqlPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(Duration::from_secs(config.acquire_timeout_secs))
+        .connect(&config.url)
+        .await
 }
-#[allow(dead_code)]
-pub async fn run_all_migrations(conn: &MySqlPool) {
-    match sqlx::migrate!("./migrations").run(conn).await {
-        Ok(()) => {
-            println!("Successfully ran migrations.")
-        }
-        Err(error) => {
-            panic!("{error}")
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Round trip to the database, fails if no connection can be acquired.
+#[instrument(level = "debug", skip_all)]
+pub async fn ping(conn: &MySqlPool) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT 1").execute(conn).await.map(|_| ())
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn run_all_migrations(conn: &MySqlPool) -> Result<(), MigrateError> {
+    MIGRATOR.run(conn).await
+}
+
+/// Migrations in ./migrations that haven't been applied yet. Doesn't write anything, not even the
+/// migrations table if it doesn't exist. Fails if an applied migration was changed after being applied.
+#[instrument(level = "debug", skip_all)]
+pub async fn pending_migrations(conn: &MySqlPool) -> Result<Vec<&'static Migration>, MigrateError> {
+    let migrations_table_exists: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = '_sqlx_migrations'",
+    )
+    .fetch_one(conn)
+    .await?;
+    let applied_migrations = match migrations_table_exists > 0 {
+        true => conn.acquire().await?.list_applied_migrations().await?,
+        false => Vec::new(),
+    };
+    let mut pending_migrations = Vec::new();
+    for migration in MIGRATOR.iter().filter(|migration| !migration.migration_type.is_down_migration()) {
+        match applied_migrations.iter().find(|applied| applied.version == migration.version) {
+            Some(applied) if applied.checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version))
+            }
+            Some(_) => {}
+            None => pending_migrations.push(migration),
         }
     }
+    Ok(pending_migrations)
 }
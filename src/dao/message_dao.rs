@@ -1,10 +1,15 @@
 use chat_types::domain::chat_message::ChatMessage;
 use sqlx::{mysql::MySqlQueryResult, MySqlPool};
+use tracing::instrument;
 
+use super::QueryTimer;
+
+#[instrument(level = "debug", skip_all)]
 pub async fn get_message(
     conn: &MySqlPool,
     message_id: &u32,
 ) -> Result<Option<ChatMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("get_message");
     match sqlx::query_file_as!(ChatMessage, "sql/message/get.sql", message_id)
         .fetch_optional(conn)
         .await
@@ -14,10 +19,12 @@ pub async fn get_message(
     }
 }
 
+#[instrument(level = "debug", skip_all)]
 pub async fn insert_message(
     conn: &MySqlPool,
     message: &ChatMessage,
 ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("insert_message");
     match sqlx::query_file!(
         "sql/message/insert.sql",
         message.from_id,
@@ -35,10 +42,12 @@ pub async fn insert_message(
     }
 }
 
+#[instrument(level = "debug", skip_all)]
 pub async fn update_message(
     conn: &MySqlPool,
     message: &ChatMessage,
 ) -> Result<MySqlQueryResult, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("update_message");
     match sqlx::query_file!(
         "sql/message/update.sql",
         message.time_delivered,
@@ -53,10 +62,12 @@ pub async fn update_message(
     }
 }
 
+#[instrument(level = "debug", skip_all)]
 pub async fn fetch_messages_with_ids(
     conn: &MySqlPool,
     message_ids: &Vec<u32>,
 ) -> Result<Vec<ChatMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let _timer = QueryTimer::start("fetch_messages_with_ids");
     let mut query = "SELECT * FROM message where id in (".to_string();
     for message_id in message_ids {
         query.push_str(&format!("{},", message_id.to_string()));
@@ -1,3 +1,29 @@
+use std::time::Instant;
+
+use metrics::histogram;
+
 pub mod chat_room_dao;
+pub mod chat_room_kick_dao;
 pub mod main_dao;
 pub mod message_dao;
+
+/// Labeled with `query`, the name of the dao function.
+pub const DB_QUERY_DURATION_METRIC: &str = "chat_db_query_duration_seconds";
+
+/// Records how long the dao function it was started in took when it's dropped, errors included.
+pub struct QueryTimer {
+    query: &'static str,
+    start: Instant,
+}
+
+impl QueryTimer {
+    pub fn start(query: &'static str) -> Self {
+        Self { query, start: Instant::now() }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        histogram!(DB_QUERY_DURATION_METRIC, self.start.elapsed().as_secs_f64(), "query" => self.query);
+    }
+}
@@ -1,5 +1,9 @@
 use chat_types::domain::chat_message::BroadcastMessage;
 use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamMap};
+
+/// Every room a socket listens to, by room id. The socket's loop polls all of them at once.
+pub type RoomSubscriptions = StreamMap<u32, BroadcastStream<BroadcastMessage>>;
 
 #[derive(Debug, Clone)]
 pub struct ChatRoomChannel {
@@ -9,6 +13,7 @@ pub struct ChatRoomChannel {
     /// para recibir los mensajes.
     /// Lo mas importante aqui es que esto sirve para elegir a quien se le va a enviar mensajes.
     /// El Tipo dentro del Sender es lo que se va a enviar a traves de los canales
+    /// Solo llega a los sockets de esta instancia, para enviar a un chat room hay que publicar con `AppState::fanout`.
     pub recipient_sockets: broadcast::Sender<BroadcastMessage>,
     pub participants: Vec<u32>,
     /// El id en la base de datos de este chat room
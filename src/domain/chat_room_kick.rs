@@ -0,0 +1,37 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A user getting kicked out of a chat room. If `banned_until` is set they can't be added back before then.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatRoomKick {
+    pub id: u32,
+    pub chat_room_id: u32,
+    pub user_id: u32,
+    pub kicked_by: u32,
+    pub reason: Option<String>,
+    pub time_kicked: DateTime<Utc>,
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+/// Optional body of the kick route.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KickRequest {
+    pub reason: Option<String>,
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+impl ChatRoomKick {
+    pub fn new(chat_room_id: u32, user_id: u32, kicked_by: u32, request: KickRequest) -> Self {
+        Self {
+            id: 0,
+            chat_room_id,
+            user_id,
+            kicked_by,
+            reason: request.reason,
+            time_kicked: Utc::now(),
+            banned_until: request.banned_until,
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt::Display;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+use sqlx::mysql::MySqlDatabaseError;
+use tokio::sync::broadcast::error::SendError;
+use tracing::error;
+
+/// Every error that can happen while handling a websocket frame or an HTTP request. Each variant maps to a
+/// stable code that gets sent back to the client (inside of a `ServerMessageOut::Error` on sockets, in the body
+/// on HTTP), so clients can react to the kind of error instead of parsing the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatError {
+    /// The user couldn't be authenticated, or tried to perform an authed action without logging in.
+    AuthFailed(String),
+    /// The user tried to act on a chat room they don't belong to.
+    NotAMember(String),
+    /// The user is a member but isn't allowed to do this (only the owner can).
+    Forbidden(String),
+    /// Whatever the user is trying to create already exists (the user is already in the room, for example).
+    Conflict(String),
+    /// The frame sent by the client is malformed or its contents don't make sense.
+    Validation(String),
+    /// The user is sending frames faster than they are allowed to.
+    RateLimited(String),
+    /// Whatever the user was looking for doesn't exist.
+    NotFound(String),
+    /// Something went wrong on our side. The message is logged but never sent to the client.
+    Internal(String),
+}
+
+impl ChatError {
+    /// The stable code sent to clients. Never change these, clients depend on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::AuthFailed(_) => "AUTH_FAILED",
+            ChatError::NotAMember(_) => "NOT_A_MEMBER",
+            ChatError::Forbidden(_) => "FORBIDDEN",
+            ChatError::Conflict(_) => "CONFLICT",
+            ChatError::Validation(_) => "VALIDATION",
+            ChatError::RateLimited(_) => "RATE_LIMITED",
+            ChatError::NotFound(_) => "NOT_FOUND",
+            ChatError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ChatError::AuthFailed(message)
+            | ChatError::NotAMember(message)
+            | ChatError::Forbidden(message)
+            | ChatError::Conflict(message)
+            | ChatError::Validation(message)
+            | ChatError::RateLimited(message)
+            | ChatError::NotFound(message)
+            | ChatError::Internal(message) => message,
+        }
+    }
+
+    /// The message that is safe to show to the client. Internal errors can contain database details.
+    pub fn client_message(&self) -> &str {
+        match self {
+            ChatError::Internal(_) => "Internal server error.",
+            _ => self.message(),
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ChatError::AuthFailed(_) => StatusCode::UNAUTHORIZED,
+            ChatError::NotAMember(_) | ChatError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ChatError::Conflict(_) => StatusCode::CONFLICT,
+            ChatError::Validation(_) => StatusCode::BAD_REQUEST,
+            ChatError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ChatError::NotFound(_) => StatusCode::NOT_FOUND,
+            ChatError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// HTTP errors have the same shape as socket errors: `{"code": "NOT_FOUND", "message": "..."}`.
+impl IntoResponse for ChatError {
+    fn into_response(self) -> Response {
+        if let ChatError::Internal(message) = &self {
+            error!(%message, "Internal error while handling HTTP request");
+        }
+        (
+            self.status_code(),
+            Json(json!({ "code": self.code(), "message": self.client_message() })),
+        )
+            .into_response()
+    }
+}
+
+impl Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+/// MySQL error numbers for constraint violations, these are on the client and not on us. Neither says which row
+/// was the problem, so the messages don't either.
+const MYSQL_DUPLICATE_KEY: u16 = 1062;
+const MYSQL_NO_REFERENCED_ROW: u16 = 1452;
+
+impl From<sqlx::Error> for ChatError {
+    fn from(error: sqlx::Error) -> Self {
+        let mysql_error_number = error
+            .as_database_error()
+            .and_then(|database_error| database_error.try_downcast_ref::<MySqlDatabaseError>())
+            .map(MySqlDatabaseError::number);
+        match mysql_error_number {
+            Some(MYSQL_DUPLICATE_KEY) => ChatError::Conflict("That already exists.".into()),
+            Some(MYSQL_NO_REFERENCED_ROW) => ChatError::NotFound("That refers to something that doesn't exist.".into()),
+            _ => ChatError::Internal(format!("Database error: {error}")),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ChatError {
+    fn from(error: serde_json::Error) -> Self {
+        ChatError::Validation(format!("Malformed frame: {error}"))
+    }
+}
+
+impl From<axum::Error> for ChatError {
+    fn from(error: axum::Error) -> Self {
+        ChatError::Internal(format!("Socket error: {error}"))
+    }
+}
+
+impl<T> From<SendError<T>> for ChatError {
+    fn from(error: SendError<T>) -> Self {
+        ChatError::Internal(format!("Couldn't broadcast message to chat room: {error}"))
+    }
+}
+
+/// The dao layer returns boxed errors. Constraint violations are the client's fault, anything else is on our side.
+impl From<Box<dyn std::error::Error + Send + Sync>> for ChatError {
+    fn from(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        match error.downcast::<sqlx::Error>() {
+            Ok(error) => (*error).into(),
+            Err(error) => ChatError::Internal(error.to_string()),
+        }
+    }
+}
@@ -1,2 +1,5 @@
 pub mod chat_room_channel;
+pub mod chat_room_kick;
+pub mod error;
+pub mod session;
 pub mod state;
\ No newline at end of file
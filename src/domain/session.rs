@@ -0,0 +1,47 @@
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::service::auth::Credentials;
+
+use super::chat_room_kick::ChatRoomKick;
+
+/// Things that happen to a session from outside of its socket, the socket's loop handles them. They go through
+/// the fanout, so they reach the user's sockets on every instance.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SessionEvent {
+    /// An admin kicked the session out.
+    Revoked,
+    /// An admin closed the socket, the credentials are still good to log in again.
+    Disconnected,
+    /// The user got kicked out of one of their rooms.
+    Kicked(ChatRoomKick),
+    /// The user left one of their rooms, from this socket or any other.
+    LeftRoom(u32),
+    /// An admin dropped one of the user's rooms from this instance's memory, the user is still in it.
+    RoomEvicted(u32),
+}
+
+/// A logged in socket. Holds on to the credentials it logged in with so they can be checked again later on,
+/// and a channel to tell the socket about things that happen elsewhere in the app.
+#[derive(Debug)]
+pub struct Session {
+    pub user_id: u32,
+    pub credentials: Credentials,
+    pub events: UnboundedSender<SessionEvent>,
+    /// Taken by the socket's loop once it's logged in.
+    pub events_receiver: Mutex<Option<UnboundedReceiver<SessionEvent>>>,
+}
+
+impl Session {
+    pub fn new(user_id: u32, credentials: Credentials) -> Self {
+        let (events, events_receiver) = unbounded_channel();
+        Self {
+            user_id,
+            credentials,
+            events,
+            events_receiver: Mutex::new(Some(events_receiver)),
+        }
+    }
+}
@@ -1,247 +1,336 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+use std::{collections::HashSet, net::{IpAddr, SocketAddr}, sync::Arc, time::Duration};
 
 use chat_types::domain::{chat_message::BroadcastMessage, chat_message_update::ChatMessageUpdate};
+use dashmap::{mapref::entry::Entry, DashMap};
 use sqlx::MySqlPool;
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::{broadcast::{self, Receiver}, mpsc::UnboundedReceiver};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::warn;
 
-use chat_types::domain::error::{MUTEX_LOCK_ERROR_MESSAGE, SocketError};
+use chat_types::domain::error::MUTEX_LOCK_ERROR_MESSAGE;
 
-use super::chat_room_channel::ChatRoomChannel;
+use crate::{config::{Config, OwnerLeavePolicy}, service::{auth::{Authenticator, Credentials}, fanout::Fanout, rate_limit::RateLimits}};
 
-const MAX_CONCURRENT_ROOM_CAPACITY: usize = 150;
+use super::{chat_room_channel::ChatRoomChannel, error::ChatError, session::{Session, SessionEvent}};
 
+/// Every map is sharded, so sockets logging in and out of different rooms don't wait on each other. A guard
+/// from one of them must never be held while touching the same map again (it can deadlock on the shard) or
+/// across an await.
 #[derive(Debug)]
 pub struct AppState {
     /// Rooms holds a map of roomId and the ChatRoomChannel object, pretty much just a list of all the ACTIVE chat rooms.
     /// Active chat rooms mean that there is at least one user connected that belongs to that chat room. If there are
     /// 0 participants of a certain group connected to a socket, the chat room must be deleted from memory.
-    pub rooms: Mutex<HashMap<u32, ChatRoomChannel>>, // An id of the room & the room object (that also holds a list of all the users)
-    pub connected_clients: Mutex<HashMap<SocketAddr, u32>>,
-    pub user_rooms: Mutex<HashMap<u32, Vec<u32>>>, // An id of the user & a list of chat room ids
+    pub rooms: DashMap<u32, ChatRoomChannel>, // An id of the room & the room object (that also holds a list of all the users)
+    pub connected_clients: DashMap<SocketAddr, u32>,
+    /// Same keys as connected_clients, the credentials each socket logged in with.
+    pub sessions: DashMap<SocketAddr, Session>,
+    /// How many sockets each IP has open that haven't logged in yet.
+    pub unauthenticated_clients: DashMap<IpAddr, usize>,
+    pub user_rooms: DashMap<u32, Vec<u32>>, // An id of the user & a list of chat room ids
     pub conn: reqwest::Client,
     pub db_conn: MySqlPool,
+    /// Used by both the socket logins and the HTTP routes, so both check credentials the same way.
+    pub authenticator: Arc<dyn Authenticator>,
+    /// How messages get to every socket in a room, on this instance or on all of them.
+    pub fanout: Arc<dyn Fanout>,
     /// Every time a message is delivered or read, it mus first query this hashmap to see if that message is currently being held by another thread.
     /// Then, If it is, add the message
-    pub message_update_queue: Mutex<HashMap<u32, Mutex<Vec<ChatMessageUpdate>>>>,
+    pub message_update_queue: DashMap<u32, Vec<ChatMessageUpdate>>,
+    /// How often every socket gets pinged.
+    pub heartbeat_interval: Duration,
+    /// How long a socket can go without sending anything (pongs included) before it gets disconnected.
+    pub heartbeat_timeout: Duration,
+    /// How long a socket has to log in before it gets disconnected.
+    pub login_timeout: Duration,
+    /// How many failed login attempts a socket gets before it gets disconnected.
+    pub max_login_attempts: u32,
+    pub max_unauthenticated_sockets_per_ip: usize,
+    /// If true, sockets have to send their credentials while opening the connection and the in band Login is not allowed.
+    pub require_handshake_auth: bool,
+    /// How often the credentials of every logged in socket get checked again.
+    pub session_revalidation_interval: Duration,
+    /// How many frames can be waiting to be written to each socket.
+    pub outbound_queue_capacity: usize,
+    /// How long writing a single frame to a socket can take.
+    pub send_timeout: Duration,
+    pub rate_limits: RateLimits,
+    /// Sockets that get rate limited more than this within a minute get disconnected.
+    pub max_rate_limit_violations_per_minute: u32,
+    /// Bearer token for the admin endpoints, they're disabled if it's not set.
+    pub admin_token: Option<String>,
+    /// Users that can see and manage every room.
+    pub admin_user_ids: HashSet<u32>,
+    /// How many messages each chat room's broadcast channel can hold.
+    pub room_channel_capacity: usize,
+    pub max_room_size: usize,
+    pub participant_lookup_url: Option<String>,
+    pub owner_leave_policy: OwnerLeavePolicy,
+    pub delete_empty_rooms: bool,
+    pub log_message_bodies: bool,
+    /// Cancelled once the server starts shutting down, every socket closes when it is.
+    pub shutdown: CancellationToken,
+    /// Sockets, their writers and the delivered/seen updates they spawn. Shutdown waits on these before
+    /// closing the database pool, anything spawned that touches the database should go through here.
+    pub tasks: TaskTracker,
 }
 
 impl AppState {
-    pub fn new(db_conn: MySqlPool, client: reqwest::Client) -> Self {
+    pub fn new(
+        db_conn: MySqlPool,
+        client: reqwest::Client,
+        authenticator: Arc<dyn Authenticator>,
+        fanout: Arc<dyn Fanout>,
+        config: &Config,
+    ) -> Self {
         Self {
             rooms: Default::default(),
             connected_clients: Default::default(),
-            conn: client,
+            sessions: Default::default(),
+            unauthenticated_clients: Default::default(),
             user_rooms: Default::default(),
+            conn: client,
             db_conn,
-            message_update_queue: Mutex::new(HashMap::new()),
+            authenticator,
+            fanout,
+            message_update_queue: Default::default(),
+            heartbeat_interval: Duration::from_secs(config.websocket.heartbeat_interval_secs),
+            heartbeat_timeout: Duration::from_secs(config.websocket.heartbeat_timeout_secs),
+            login_timeout: Duration::from_secs(config.websocket.login_timeout_secs),
+            max_login_attempts: config.limits.max_login_attempts,
+            max_unauthenticated_sockets_per_ip: config.limits.max_unauthenticated_sockets_per_ip,
+            require_handshake_auth: config.auth.require_handshake_auth,
+            session_revalidation_interval: Duration::from_secs(config.websocket.session_revalidation_secs),
+            outbound_queue_capacity: config.websocket.outbound_queue_capacity,
+            send_timeout: Duration::from_secs(config.websocket.send_timeout_secs),
+            rate_limits: RateLimits::new(&config.rate_limits),
+            max_rate_limit_violations_per_minute: config.rate_limits.max_violations_per_minute,
+            admin_token: config.auth.admin_token.clone(),
+            admin_user_ids: config.auth.admin_user_ids.iter().copied().collect(),
+            room_channel_capacity: config.limits.room_channel_capacity,
+            max_room_size: config.rooms.max_size,
+            participant_lookup_url: config.rooms.participant_lookup_url.clone(),
+            owner_leave_policy: config.rooms.owner_leave_policy,
+            delete_empty_rooms: config.rooms.delete_empty_rooms,
+            log_message_bodies: config.logging.log_message_bodies,
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
         }
     }
 
+    pub fn is_admin(&self, user_id: &u32) -> bool {
+        self.admin_user_ids.contains(user_id)
+    }
     pub fn add_connected_client(
         &self,
         addr: SocketAddr,
         user_id: u32,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // lock mutex
-        let mut connected_clients = self
-            .connected_clients
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        match connected_clients.insert(addr, user_id) {
-            Some(_) => Err(SocketError::boxed_error("Existing socket connected client replaced by another user id, this should NOT be happening. FATAL!")),
+    ) -> Result<(), ChatError> {
+        match self.connected_clients.insert(addr, user_id) {
+            Some(_) => Err(ChatError::Internal("Existing socket connected client replaced by another user id, this should NOT be happening. FATAL!".into())),
             None => Ok(()),
         }
     }
+    pub fn add_session(&self, addr: SocketAddr, user_id: u32, credentials: Credentials) {
+        self.sessions.insert(addr, Session::new(user_id, credentials));
+    }
+    pub fn remove_session(&self, addr: &SocketAddr) {
+        self.sessions.remove(addr);
+    }
+    pub fn get_session_credentials(&self, addr: &SocketAddr) -> Option<Credentials> {
+        self.sessions.get(addr).map(|session| session.credentials.clone())
+    }
+    /// Can only be taken once, by the socket the session belongs to.
+    pub fn take_session_events(&self, addr: &SocketAddr) -> Option<UnboundedReceiver<SessionEvent>> {
+        self.sessions
+            .get(addr)
+            .and_then(|session| session.events_receiver.lock().expect(MUTEX_LOCK_ERROR_MESSAGE).take())
+    }
+    /// Sends the event to every socket the user is logged in with on this instance. Returns how many there were.
+    /// Everything else should go through `publish_session_event` so sockets on other instances get it too.
+    pub fn send_session_event(&self, user_id: &u32, event: SessionEvent) -> usize {
+        self.sessions
+            .iter()
+            .filter(|session| session.user_id == *user_id)
+            .filter(|session| session.events.send(event.clone()).is_ok())
+            .count()
+    }
+    /// Sends the event to every socket the user is logged in with, on every instance. Whatever caused the event
+    /// has already happened by now, so if the fanout fails the sockets on this instance still get it.
+    pub async fn publish_session_event(&self, user_id: u32, event: SessionEvent) {
+        if let Err(error) = self.fanout.publish_session_event(self, user_id, event.clone()).await {
+            warn!(%error, user_id, "Couldn't publish session event, only sockets on this instance get it");
+            self.send_session_event(&user_id, event);
+        }
+    }
+    /// Tells every socket the user is logged in with to close.
+    pub async fn revoke_user_sessions(&self, user_id: u32) {
+        self.publish_session_event(user_id, SessionEvent::Revoked).await
+    }
+    /// Closes every socket the user is logged in with, without revoking anything.
+    pub async fn disconnect_user(&self, user_id: u32) {
+        self.publish_session_event(user_id, SessionEvent::Disconnected).await
+    }
+    /// Drops the room from this instance's memory, every socket on this instance that was listening to it gets
+    /// unsubscribed and told so. Only this instance is affected and nothing changes in the database: participants
+    /// stay members, can keep sending to the room, and get its messages again the next time they log in. Returns
+    /// who was in it, None if the room wasn't active.
+    pub fn evict_room(&self, room_id: &u32) -> Option<Vec<u32>> {
+        // Taken out in one go, a login racing with this either makes it in before and gets told, or starts a new
+        // channel after.
+        let (_, room) = self.rooms.remove(room_id)?;
+        let mut participants = room.participants;
+        participants.sort_unstable();
+        participants.dedup();
+        for user_id in &participants {
+            self.send_session_event(user_id, SessionEvent::RoomEvicted(*room_id));
+        }
+        Some(participants)
+    }
+    /// Counts a socket that hasn't logged in yet against its IP. Fails if that IP already has too many of them.
+    pub fn add_unauthenticated_client(&self, ip: IpAddr) -> Result<(), ChatError> {
+        let mut unauthenticated_sockets = self.unauthenticated_clients.entry(ip).or_insert(0);
+        if *unauthenticated_sockets >= self.max_unauthenticated_sockets_per_ip {
+            return Err(ChatError::RateLimited(
+                "Too many unauthenticated connections from this address.".into(),
+            ));
+        }
+        *unauthenticated_sockets += 1;
+        Ok(())
+    }
+    /// Gets called when an unauthenticated socket logs in or disconnects.
+    pub fn remove_unauthenticated_client(&self, ip: &IpAddr) {
+        if let Entry::Occupied(mut unauthenticated_sockets) = self.unauthenticated_clients.entry(*ip) {
+            *unauthenticated_sockets.get_mut() -= 1;
+            if *unauthenticated_sockets.get() == 0 {
+                unauthenticated_sockets.remove();
+            }
+        }
+    }
     pub fn add_user_with_rooms(
         &self,
         user_id: u32,
         rooms: Vec<u32>,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut user_rooms = self.user_rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        if user_rooms.contains_key(&user_id) {
-            return Err(SocketError::boxed_error("Existing user_rooms was attempted to be replaced by another list of rooms, this usually happens when a user logs in from 2 clients at the same time."));
-        };
-        match user_rooms.insert(user_id, rooms) {
-            Some(_) => Err(SocketError::boxed_error("Existing user_rooms replaced by another list of rooms, this should NOT be happening. FATAL!")),
-            None => Ok(()),
+    ) -> Result<(), ChatError> {
+        match self.user_rooms.entry(user_id) {
+            Entry::Occupied(_) => Err(ChatError::Validation("Existing user_rooms was attempted to be replaced by another list of rooms, this usually happens when a user logs in from 2 clients at the same time.".into())),
+            Entry::Vacant(entry) => {
+                entry.insert(rooms);
+                Ok(())
+            }
         }
     }
+    /// Adds the user to the room's channel, creating it if they're the first one online, and subscribes to it.
+    /// Both happen under the room's shard lock so the room can't be removed in between.
     pub fn add_chat_room_channel(
         &self,
         room_id: u32,
         user_id: &u32,
-    ) -> Result<Receiver<BroadcastMessage>, Box<dyn std::error::Error + Send + Sync>> {
-        let mut chat_rooms = self.rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        let (tx, rx) = broadcast::channel(MAX_CONCURRENT_ROOM_CAPACITY);
-        let chat_room_channel = ChatRoomChannel::new(tx, vec![*user_id], room_id);
-
-        match chat_rooms.get_mut (&room_id) {
-            Some(existing_chat_room) => {
-                existing_chat_room.participants.push(*user_id);
-                Ok(rx)
-            },
-            None => {
-                match chat_rooms.insert(room_id, chat_room_channel) {
-                    Some(_) => Err(SocketError::boxed_error(
-                        "Existing chat_room replaced by another room, this should NOT be happening. FATAL!",
-                    )),
-                    None => Ok(rx),
-                }
-            },
-        }
-    }
-    /*pub fn get_cloned_chat_room_channel(&self, room_id: &u32) -> Option<ChatRoomChannel> {
-        let chat_rooms = self.rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        chat_rooms.get(room_id).cloned()
-    }*/
-    pub fn subscribe_to_channel(
-        &self,
-        room_id: &u32,
-    ) -> Result<Receiver<BroadcastMessage>, Box<dyn std::error::Error + Send + Sync>> {
-        let chat_rooms = self.rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        match chat_rooms.get(room_id) {
-            Some(chat_room_channel) => Ok(chat_room_channel.recipient_sockets.subscribe()),
-            None => Err(SocketError::boxed_error(
-                "No chat rooms found with that id. When attempting to subscribe to a channel.",
-            )),
-        }
+    ) -> Result<Receiver<BroadcastMessage>, ChatError> {
+        let mut chat_room_channel = self.rooms.entry(room_id).or_insert_with(|| {
+            let (tx, _) = broadcast::channel(self.room_channel_capacity);
+            ChatRoomChannel::new(tx, Vec::new(), room_id)
+        });
+        chat_room_channel.participants.push(*user_id);
+        Ok(chat_room_channel.recipient_sockets.subscribe())
     }
-    pub fn get_cloned_broadcast_sender_to_chat_room(
+    /// Sends to the sockets on this instance that are subscribed to the room. Everything else should publish
+    /// through `fanout` so sockets on other instances get it too.
+    pub fn broadcast_to_local_subscribers(
         &self,
         room_id: &u32,
-    ) -> Result<Sender<BroadcastMessage>, Box<dyn std::error::Error + Send + Sync>> {
-        let chat_rooms = self.rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        match chat_rooms.get(room_id) {
-            Some(chat_room_channel) => Ok(chat_room_channel.recipient_sockets.clone()),
-            None => Err(SocketError::boxed_error("No chat rooms found with that id. When attempting to get cloned sender from channel.")),
+        message: BroadcastMessage,
+    ) -> Result<usize, ChatError> {
+        match self.rooms.get(room_id) {
+            Some(chat_room_channel) => Ok(chat_room_channel.recipient_sockets.send(message)?),
+            None => Err(ChatError::NotFound("No chat rooms found with that id. When attempting to broadcast to a channel.".into())),
         }
     }
 
     pub fn remove_connected_client(
         &self,
         addr: &SocketAddr,
-    ) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
-        let mut connected_clients = self
-            .connected_clients
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        match connected_clients.remove(addr) {
-            Some(removed_user_id) => Ok(removed_user_id),
-            None => Err(SocketError::boxed_error("No user tied to that Address.")),
+    ) -> Result<u32, ChatError> {
+        match self.connected_clients.remove(addr) {
+            Some((_, removed_user_id)) => Ok(removed_user_id),
+            None => Err(ChatError::NotFound("No user tied to that Address.".into())),
         }
     }
     pub fn remove_user_from_all_groups(
         &self,
         user_id: &u32,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        // Get all rooms user is in
-        // Remove that entry from the user -> rooms map
-        // Go into each ChatRoomChannel and remove the user as a participant
-        let mut user_rooms = self.user_rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        let rooms_user_is_in = match user_rooms.remove(user_id) {
-            Some(rooms) => rooms,
-            None => return Err(SocketError::boxed_error("No rooms tied to that user_id.")),
+    ) -> Result<(), ChatError> {
+        // Remove the user -> rooms entry, then take the user out of each ChatRoomChannel. Every room gets locked
+        // on its own, so this doesn't hold up logins to the user's other rooms.
+        let rooms_user_is_in = match self.user_rooms.remove(user_id) {
+            Some((_, rooms)) => rooms,
+            None => return Err(ChatError::NotFound("No rooms tied to that user_id.".into())),
         };
         for room_id in rooms_user_is_in {
-            let mut chat_room_channels = self.rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-            let room = match chat_room_channels.get_mut(&room_id) {
-                Some(chat_room_channel) => chat_room_channel,
-                None => {
-                    return Err(SocketError::boxed_error(
-                        "No chat_room_channels found with that room id...",
-                    ))
-                }
-            };
-            let user_id_pos_to_remove: usize = match room
-                .participants
-                .iter()
-                .position(|user_in_room_id| user_in_room_id == user_id)
-            {
-                Some(user_id) => user_id,
-                None => {
-                    return Err(SocketError::boxed_error(
-                        "No participants found with that user_id inside the chat room...",
-                    ))
-                }
-            };
-            room.participants.remove(user_id_pos_to_remove);
-            if room.participants.is_empty() {
-                chat_room_channels.remove(&room_id);
-            }
+            self.remove_participant_from_channel(&room_id, user_id);
         }
         Ok(())
     }
+    /// Takes a user that's online out of a single room, for when they leave it or get kicked while connected.
+    pub fn remove_user_from_room(&self, user_id: &u32, room_id: &u32) {
+        match self.user_rooms.get_mut(user_id) {
+            Some(mut rooms_user_is_in) if rooms_user_is_in.contains(room_id) => {
+                rooms_user_is_in.retain(|user_room_id| user_room_id != room_id);
+            }
+            _ => return,
+        }
+        self.remove_participant_from_channel(room_id, user_id);
+    }
+    /// Rooms are dropped from memory as soon as nobody in them is online.
+    fn remove_participant_from_channel(&self, room_id: &u32, user_id: &u32) {
+        if let Entry::Occupied(mut room) = self.rooms.entry(*room_id) {
+            let participants = &mut room.get_mut().participants;
+            if let Some(position) = participants.iter().position(|participant| participant == user_id) {
+                participants.remove(position);
+            }
+            if participants.is_empty() {
+                room.remove();
+            }
+        }
+    }
     pub fn get_all_user_chat_rooms(&self, user_id: &u32) -> Option<Vec<u32>> {
-        let user_rooms = self.user_rooms.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        user_rooms.get(user_id).cloned()
+        self.user_rooms.get(user_id).map(|rooms| rooms.clone())
     }
     pub fn does_message_have_updates_in_queue(&self, message_id: &u32) -> bool {
-        match self
-            .message_update_queue
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE)
-            .get(message_id)
-        {
-            Some(_) => true,
-            None => false,
-        }
+        self.message_update_queue.contains_key(message_id)
     }
     pub fn add_message_update_to_queue(&self, message_id: &u32, update: ChatMessageUpdate) {
-        let mut message_update_queue_lock = self
-            .message_update_queue
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        let message_update_queue = match message_update_queue_lock.get(message_id) {
-            Some(message_update_queue) => message_update_queue,
-            None => {
-                message_update_queue_lock.insert(*message_id, Mutex::new(vec![update]));
-                return;
-            }
-        };
-        let mut message_updates = message_update_queue.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
-        message_updates.push(update);
+        self.message_update_queue.entry(*message_id).or_default().push(update);
     }
     pub fn remove_first_message_update_from_queue(
         &self,
         message_id: &u32,
     ) -> Option<ChatMessageUpdate> {
-        let mut message_update_queue_lock = self
-            .message_update_queue
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        let mut message_update_queue = message_update_queue_lock
-            .get_mut(message_id)?
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        if message_update_queue.len() == 0 {
-            drop(message_update_queue);
-            message_update_queue_lock.remove(message_id);
-            return None;
-        }
-        let removed_message = message_update_queue.remove(0);
+        let mut message_update_queue = match self.message_update_queue.entry(*message_id) {
+            Entry::Occupied(message_update_queue) => message_update_queue,
+            Entry::Vacant(_) => return None,
+        };
+        let removed_message = match message_update_queue.get().is_empty() {
+            true => None,
+            false => Some(message_update_queue.get_mut().remove(0)),
+        };
         // delete message update queue if it's empty
-        if message_update_queue.len() == 0 {
-            drop(message_update_queue);
-            message_update_queue_lock.remove(message_id);
+        if message_update_queue.get().is_empty() {
+            message_update_queue.remove();
         }
-        Some(removed_message)
+        removed_message
     }
     pub fn is_update_first_in_queue(&self, message_id: &u32, update: &ChatMessageUpdate) -> bool {
-        let mut message_update_queue_lock = self
-            .message_update_queue
-            .lock()
-            .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        let message_update_queue = match message_update_queue_lock.get(message_id) {
-            Some(message_update_queue) => message_update_queue,
-            None => return false,
-        }
-        .lock()
-        .expect(MUTEX_LOCK_ERROR_MESSAGE);
-        if message_update_queue.len() == 0 {
-            drop(message_update_queue);
-            message_update_queue_lock.remove(message_id);
-            return false;
+        let message_update_queue = match self.message_update_queue.entry(*message_id) {
+            Entry::Occupied(message_update_queue) => message_update_queue,
+            Entry::Vacant(_) => return false,
+        };
+        match message_update_queue.get().first() {
+            Some(first_update) => first_update == update,
+            None => {
+                message_update_queue.remove();
+                false
+            }
         }
-        message_update_queue.first().unwrap() == update
     }
 }
@@ -1,3 +1,5 @@
+mod cli;
+mod config;
 mod dao;
 mod domain;
 mod net;
@@ -5,18 +7,30 @@ mod routes;
 mod service;
 mod util;
 
+use std::process::ExitCode;
+
+use clap::Parser;
+
 use crate::{
-    dao::main_dao, routes::http::main_router::start_http_server, net::websocket::start_ws_server,
+    cli::{Cli, EXIT_INVALID_CONFIG},
+    config::Config,
 };
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenvy::dotenv().ok();
 
-    let database_pool = main_dao::start_database_connection().await.unwrap();
-    let client_pool = reqwest::Client::new();
-    let _ = tokio::join!(
-        start_ws_server(database_pool.clone(), client_pool.clone()),
-        start_http_server(database_pool.clone(), client_pool.clone())
-    );
+    let cli = Cli::parse();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}");
+            return ExitCode::from(EXIT_INVALID_CONFIG);
+        }
+    };
+    util::logging::init(&config.logging);
+    match cli::run(cli.command.unwrap_or_default(), config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(exit_code) => exit_code,
+    }
 }
@@ -0,0 +1,188 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chat_types::domain::chat_message_update::ChatMessageUpdate;
+use serde_json::{json, Value};
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use crate::domain::{error::ChatError, state::AppState};
+
+/// Admin endpoints (and `/metrics`) need `Authorization: Bearer <ADMIN_TOKEN>`. If ADMIN_TOKEN isn't set nobody
+/// gets in. The token is compared in constant time so response times don't give away how much of it was right.
+pub(crate) fn is_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    let admin_token = match &state.admin_token {
+        // Config validation rejects empty tokens, this is in case that ever changes.
+        Some(admin_token) if !admin_token.is_empty() => admin_token,
+        _ => return false,
+    };
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .is_some_and(|token| token.as_bytes().ct_eq(admin_token.as_bytes()).into())
+}
+
+/// Closes every socket the user is logged in with, for when their token gets revoked and they shouldn't
+/// have to wait for the next re-validation to get kicked out.
+pub async fn revoke_user_sessions(
+    Path(user_id): Path<u32>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    state.revoke_user_sessions(user_id).await;
+    info!(user_id, "Admin revoked the user's sessions");
+    Json(json!({ "userId": user_id })).into_response()
+}
+
+/// Every user logged in on this instance, the addresses of their sockets and the rooms they get messages from.
+pub async fn get_connected_users(headers: HeaderMap, State(state): State<Arc<AppState>>) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let mut addrs_by_user: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for connected_client in state.connected_clients.iter() {
+        addrs_by_user
+            .entry(*connected_client.value())
+            .or_default()
+            .push(connected_client.key().to_string());
+    }
+    let users: Vec<Value> = addrs_by_user
+        .into_iter()
+        .map(|(user_id, addrs)| {
+            json!({
+                "userId": user_id,
+                "addrs": addrs,
+                "chatRoomIds": state.get_all_user_chat_rooms(&user_id).unwrap_or_default(),
+            })
+        })
+        .collect();
+    Json(users).into_response()
+}
+
+/// Every room with a channel in memory, who's online in it and how many sockets are subscribed to its channel.
+pub async fn get_active_rooms(headers: HeaderMap, State(state): State<Arc<AppState>>) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let mut rooms: Vec<(u32, Value)> = state
+        .rooms
+        .iter()
+        .map(|room| {
+            let room_json = json!({
+                "chatRoomId": room.chat_room_id,
+                "participants": room.participants,
+                "subscribers": room.recipient_sockets.receiver_count(),
+            });
+            (room.chat_room_id, room_json)
+        })
+        .collect();
+    rooms.sort_by_key(|(chat_room_id, _)| *chat_room_id);
+    Json(rooms.into_iter().map(|(_, room)| room).collect::<Vec<_>>()).into_response()
+}
+
+/// Delivered and seen updates still waiting to be written, by message, first one in line first.
+pub async fn get_message_update_queue(headers: HeaderMap, State(state): State<Arc<AppState>>) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let mut queue: Vec<(u32, Value)> = state
+        .message_update_queue
+        .iter()
+        .map(|updates| {
+            let updates_json: Vec<Value> = updates.value().iter().map(message_update_json).collect();
+            (*updates.key(), json!({ "messageId": updates.key(), "updates": updates_json }))
+        })
+        .collect();
+    queue.sort_by_key(|(message_id, _)| *message_id);
+    Json(queue.into_iter().map(|(_, updates)| updates).collect::<Vec<_>>()).into_response()
+}
+
+fn message_update_json(update: &ChatMessageUpdate) -> Value {
+    match update {
+        ChatMessageUpdate::Delivered(user_id, time) => json!({ "type": "delivered", "userId": user_id, "time": time }),
+        ChatMessageUpdate::Seen(user_id, time) => json!({ "type": "seen", "userId": user_id, "time": time }),
+    }
+}
+
+/// Closes every socket the user has, on every instance, with code 4005. Unlike revoking their sessions, they can
+/// log right back in.
+pub async fn disconnect_user(
+    Path(user_id): Path<u32>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    state.disconnect_user(user_id).await;
+    info!(user_id, "Admin disconnected the user");
+    Json(json!({ "userId": user_id })).into_response()
+}
+
+/// Drops the room's channel from this instance's memory and unsubscribes everyone online in it on this instance,
+/// see `AppState::evict_room`.
+pub async fn evict_room(
+    Path(chat_room_id): Path<u32>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    match state.evict_room(&chat_room_id) {
+        Some(participants) => {
+            info!(chat_room_id, evicted_participants = participants.len(), "Admin evicted the room");
+            Json(json!({ "chatRoomId": chat_room_id, "evictedParticipants": participants })).into_response()
+        }
+        None => ChatError::NotFound("That chat room isn't active.".into()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use sqlx::MySqlPool;
+
+    use crate::util::test_util::{connect_as, create_room, next_frame_with_head, send, serve, test_router, test_state};
+
+    const OWNER_ID: u32 = 1;
+    const MEMBER_ID: u32 = 2;
+
+    #[sqlx::test]
+    async fn evicted_rooms_are_dropped_and_their_members_told(pool: MySqlPool) {
+        let state = test_state(pool, |config| config.auth.admin_token = Some("secret".into()));
+        let router = test_router(state.clone());
+        let addr = serve(state.clone());
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let mut socket = connect_as(&state, addr, MEMBER_ID).await;
+        assert!(state.rooms.contains_key(&room_id));
+
+        let evict = Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("/admin/rooms/{room_id}"))
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let (status, evicted) = send(&router, evict).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(evicted["evictedParticipants"], serde_json::json!([MEMBER_ID]));
+
+        let frame = next_frame_with_head(&mut socket, "ROOM EVICTED").await;
+        assert_eq!(frame["body"]["chatRoomId"], room_id);
+        assert!(!state.rooms.contains_key(&room_id));
+        // Still a member, only the channel is gone.
+        assert_eq!(state.get_all_user_chat_rooms(&MEMBER_ID), Some(vec![room_id]));
+    }
+}
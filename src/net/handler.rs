@@ -1,33 +1,80 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::Message;
 use chat_types::{domain::chat_message::BroadcastMessage, dto::{server_in::ServerMessageIn, server_out::ServerMessageOut}};
-use futures::stream::SplitSink;
-use tokio::{sync::Mutex, task::JoinHandle};
+use serde_json::Value;
+use tracing::{debug, info_span, Instrument};
 
 use crate::{
-    domain::state::AppState,
+    domain::{chat_room_channel::RoomSubscriptions, error::ChatError, state::AppState},
     service::{
-        message::{see_messages, user_send_message},
+        message::{mark_message_delivered, see_messages, user_send_message},
         user::{is_addr_registered, register_addr},
     },
 };
 
-use super::utils::{interpret_message, send_message};
+use super::{
+    outbound::Outbound,
+    utils::{interpret_message, send_error, send_message, send_pong, send_reply, InboundFrame},
+};
 
+/// Handles a single frame sent by the client. Every direct reply echoes the request id of the frame. If handling
+/// it fails, the error gets sent back to the client with its code, then returned so the caller can log it.
+/// Frame bodies only get logged if `log_message_bodies` is on.
 pub async fn handle_message(
     message: Message,
-    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    sender: &Outbound,
     state: Arc<AppState>,
     addr: SocketAddr,
-    all_send_tasks: &mut Vec<JoinHandle<()>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    
-    let client_message_in = match interpret_message(message) {
-        Ok(res) => res,
-        Err(err) => return Err(err),
+    subscriptions: &mut RoomSubscriptions,
+) -> Result<(), ChatError> {
+    // Control frames are answered by axum itself and pongs are tracked by the heartbeat, nothing else to do with them.
+    if let Message::Ping(_) | Message::Pong(_) | Message::Close(_) = message {
+        return Ok(());
+    }
+    if state.log_message_bodies {
+        if let Message::Text(text) = &message {
+            debug!(body = %text, "Frame received");
+        }
+    }
+
+    let (request_id, client_message_in) = interpret_message(message);
+
+    let result = match client_message_in {
+        Ok(InboundFrame::Ping) => send_pong(sender, &request_id).await,
+        Ok(InboundFrame::Message(client_message_in)) => {
+            let span = info_span!("message", kind = message_kind(&client_message_in));
+            process_message(client_message_in, &request_id, sender, state, addr, subscriptions)
+                .instrument(span)
+                .await
+        }
+        Err(error) => Err(error),
     };
 
+    if let Err(error) = &result {
+        send_error(sender, error, &request_id).await?;
+    }
+    result
+}
+
+fn message_kind(message: &ServerMessageIn) -> &'static str {
+    match message {
+        ServerMessageIn::Login(_) => "login",
+        ServerMessageIn::Logout => "logout",
+        ServerMessageIn::SeeMessages(_) => "see_messages",
+        ServerMessageIn::SendMessage(_) => "send_message",
+        ServerMessageIn::FetchMessages() => "fetch_messages",
+    }
+}
+
+async fn process_message(
+    client_message_in: ServerMessageIn,
+    request_id: &Option<Value>,
+    sender: &Outbound,
+    state: Arc<AppState>,
+    addr: SocketAddr,
+    subscriptions: &mut RoomSubscriptions,
+) -> Result<(), ChatError> {
     let user_id = match is_addr_registered(&state, &addr) {
         Some(user_id) => user_id,
         None => {
@@ -36,19 +83,22 @@ pub async fn handle_message(
                 &addr,
                 sender,
                 &client_message_in,
-                all_send_tasks,
+                request_id,
+                subscriptions,
             )
             .await
         }
     };
 
+    if let ServerMessageIn::SendMessage(message) = &client_message_in {
+        if !state.get_all_user_chat_rooms(&user_id).unwrap_or_default().contains(&message.to) {
+            return Err(ChatError::NotAMember("User doesn't belong to this chat room.".into()));
+        }
+    }
+    state.rate_limits.check_message(user_id, &client_message_in)?;
     match client_message_in {
         ServerMessageIn::Login(_) => {
-            send_message(
-                sender,
-                ServerMessageOut::Error("Already Logged in!".into()),
-            )
-            .await?
+            return Err(ChatError::Validation("Already Logged in!".into()));
         }
         ServerMessageIn::Logout => return Ok(()), //TODO: Make this method, should be easy, just disconnect client?
         ServerMessageIn::SeeMessages(seen_messages) => {
@@ -56,7 +106,7 @@ pub async fn handle_message(
         }
         ServerMessageIn::SendMessage(message) => {
             user_send_message(state, user_id, BroadcastMessage::NewMessageRequest(message)).await?;
-            send_message(sender, ServerMessageOut::MessageSent).await?;
+            send_reply(sender, ServerMessageOut::MessageSent, request_id).await?;
         }
         ServerMessageIn::FetchMessages() => todo!(),
     };
@@ -64,19 +114,42 @@ pub async fn handle_message(
     Ok(())
 }
 
+/// Pushes a message broadcast to one of the user's rooms down their socket. New messages also get marked as
+/// delivered to the user, in the background so the socket doesn't wait on the database.
+pub async fn forward_room_message(
+    state: &Arc<AppState>,
+    sender: &Outbound,
+    user_id: u32,
+    message: BroadcastMessage,
+) -> Result<(), ChatError> {
+    let message_to_send_to_client = match message.clone() {
+        BroadcastMessage::NewMessage(message) => ServerMessageOut::MessageRecieved(message),
+        BroadcastMessage::DeliveredUpdate(delivered_update) => ServerMessageOut::MessageDelivered(delivered_update),
+        BroadcastMessage::SeenUpdate(seen_update) => ServerMessageOut::MessageSeen(seen_update),
+        BroadcastMessage::NewMessageRequest(message_req) => {
+            return Err(ChatError::Internal(format!(
+                "New message request being sent to individual users. This is prohibited. Message attempting to be sent: {:?}",
+                message_req
+            )))
+        }
+    };
+    send_message(sender, message_to_send_to_client).await?;
+    if let BroadcastMessage::NewMessage(message) = message {
+        state.tasks.spawn(mark_message_delivered(state.clone(), user_id, message.id).in_current_span());
+    }
+    Ok(())
+}
+
 /// All the logic that needs to happen whenever a client gets disconnected from the server.
 pub async fn disconnect_client(
     state: &Arc<AppState>,
     addr: &SocketAddr,
-    send_tasks: Vec<JoinHandle<()>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    for send_task in send_tasks {
-        send_task.abort();
-    }
+) -> Result<(), ChatError> {
+    state.remove_session(addr);
     match state.remove_connected_client(addr) {
         Ok(user_id) => state.remove_user_from_all_groups(&user_id)?,
         Err(error) => return Err(error),
     };
-    
+
     Ok(())
 }
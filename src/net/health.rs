@@ -0,0 +1,74 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{dao::main_dao, domain::state::AppState};
+
+/// How long each readiness check gets before it counts as failed.
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Liveness, answers as long as the process is serving requests.
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Readiness: the database answers and logins can work (see `Authenticator::ready`). Pending migrations keep the
+/// server from starting at all, so they aren't checked here. Answers 503 with which checks failed otherwise, and as
+/// soon as the server starts shutting down so no new sockets get routed here. Why a check failed only goes to the
+/// log, the endpoint is public.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let (database, auth) = tokio::join!(check(main_dao::ping(&state.db_conn)), check(state.authenticator.ready()));
+    let shutting_down = state.shutdown.is_cancelled();
+    let ready = !shutting_down && [&database, &auth].iter().all(|check| check.is_ok());
+    let status_code = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    if !ready && !shutting_down {
+        warn!(?database, ?auth, "Not ready");
+    }
+    let body = json!({
+        "ready": ready,
+        "shuttingDown": shutting_down,
+        "checks": {
+            "database": check_result(database),
+            "auth": check_result(auth),
+        },
+    });
+    (status_code, Json(body))
+}
+
+async fn check<E: ToString>(check: impl Future<Output = Result<(), E>>) -> Result<(), String> {
+    match tokio::time::timeout(READINESS_CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(error)) => Err(error.to_string()),
+        Err(_) => Err(format!("timed out after {}s", READINESS_CHECK_TIMEOUT.as_secs())),
+    }
+}
+
+fn check_result(result: Result<(), String>) -> Value {
+    match result {
+        Ok(()) => json!("ok"),
+        Err(_) => json!("failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+
+    use crate::util::test_util::{lazy_pool, request_as, test_router, test_state};
+
+    #[tokio::test]
+    async fn failed_checks_dont_say_why() {
+        let router = test_router(test_state(lazy_pool(), |_| {}));
+        let (status, body) = request_as(&router, 1, Method::GET, "/readyz", None).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["checks"]["database"], "failed");
+        assert!(body["checks"].get("migrations").is_none());
+        assert_eq!(body["checks"]["auth"], "ok");
+    }
+}
@@ -0,0 +1,108 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use metrics::{gauge, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::domain::state::AppState;
+
+use super::admin::is_admin;
+
+/// Open sockets, logged in or not.
+pub const CONNECTED_SOCKETS_METRIC: &str = "chat_connected_sockets";
+/// Users with at least one logged in socket on this instance.
+pub const AUTHENTICATED_USERS_METRIC: &str = "chat_authenticated_users";
+/// Rooms with a ChatRoomChannel in memory, that is with at least one participant connected to this instance.
+pub const ACTIVE_CHANNELS_METRIC: &str = "chat_active_room_channels";
+/// Delivered/seen updates waiting in `message_update_queue`, across every message.
+pub const MESSAGE_UPDATE_QUEUE_DEPTH_METRIC: &str = "chat_message_update_queue_depth";
+/// Labeled with `method`, `route` (the pattern, e.g. `/chat/room/:room/leave`) and `status`.
+pub const HTTP_REQUEST_DURATION_METRIC: &str = "chat_http_request_duration_seconds";
+
+/// Every `*_duration_seconds` histogram gets these, from a fast query up to a request that's about to time out.
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Makes every metric recorded from now on show up in `/metrics`. Can only be done once per process.
+pub fn install_recorder() -> Result<PrometheusHandle, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_duration_seconds".to_string()), DURATION_BUCKETS)?
+        .install_recorder()
+}
+
+/// Prometheus text format. The gauges that mirror AppState get read right before rendering, so they're as fresh
+/// as the scrape. Needs the admin token like the admin endpoints, scrapers send it as a bearer token.
+pub async fn metrics(
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+    Extension(handle): Extension<PrometheusHandle>,
+) -> Response {
+    if !is_admin(&state, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let unauthenticated_sockets: usize = state.unauthenticated_clients.iter().map(|sockets| *sockets.value()).sum();
+    gauge!(CONNECTED_SOCKETS_METRIC, (state.connected_clients.len() + unauthenticated_sockets) as f64);
+    gauge!(AUTHENTICATED_USERS_METRIC, state.user_rooms.len() as f64);
+    gauge!(ACTIVE_CHANNELS_METRIC, state.rooms.len() as f64);
+    let queued_updates: usize = state.message_update_queue.iter().map(|updates| updates.value().len()).sum();
+    gauge!(MESSAGE_UPDATE_QUEUE_DEPTH_METRIC, queued_updates as f64);
+    handle.render().into_response()
+}
+
+/// Times every request that matched a route. Sockets only count until the upgrade.
+pub async fn track_http_duration<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(matched_path) => matched_path.as_str().to_string(),
+        None => "unmatched".to_string(),
+    };
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    histogram!(
+        HTTP_REQUEST_DURATION_METRIC,
+        start.elapsed().as_secs_f64(),
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+
+    use crate::util::test_util::{lazy_pool, send, test_router, test_state};
+
+    fn scrape(authorization: Option<&str>) -> Request<Body> {
+        let request = Request::builder().uri("/metrics");
+        let request = match authorization {
+            Some(authorization) => request.header(header::AUTHORIZATION, authorization),
+            None => request,
+        };
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_need_the_admin_token() {
+        let router = test_router(test_state(lazy_pool(), |config| config.auth.admin_token = Some("secret".into())));
+        assert_eq!(send(&router, scrape(None)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&router, scrape(Some("Bearer wrong"))).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&router, scrape(Some("Bearer secret"))).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_closed_without_an_admin_token() {
+        let router = test_router(test_state(lazy_pool(), |_| {}));
+        assert_eq!(send(&router, scrape(None)).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(send(&router, scrape(Some("Bearer "))).await.0, StatusCode::FORBIDDEN);
+    }
+}
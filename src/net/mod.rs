@@ -1,3 +1,8 @@
+pub mod admin;
 pub mod handler;
+pub mod handshake;
+pub mod health;
+pub mod metrics;
+pub mod outbound;
 pub mod utils;
 pub mod websocket;
@@ -0,0 +1,191 @@
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use axum::extract::ws::{CloseFrame, Message};
+use futures::{Sink, SinkExt};
+use tokio::{
+    sync::{
+        mpsc::{
+            self,
+            error::{SendTimeoutError, TrySendError},
+        },
+        Notify,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::task::TaskTracker;
+use tracing::{info, Instrument};
+
+use crate::domain::error::ChatError;
+
+use super::utils::SLOW_CONSUMER_CLOSE_CODE;
+
+/// Counts sockets closed because the client couldn't keep up, labeled with `reason` (`queue_full` or `send_timeout`).
+pub const SLOW_CONSUMER_DISCONNECTS_METRIC: &str = "chat_slow_consumer_disconnects_total";
+
+/// Everything that goes out to a socket gets queued here and written by a single task, so sending never waits
+/// on the client. Cheap to clone, every clone feeds the same queue.
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    queue: mpsc::Sender<Message>,
+    queue_full: Arc<Notify>,
+    send_timeout: Duration,
+}
+
+impl Outbound {
+    /// Spawns the task that writes to the socket. It stops after writing a close frame, once the socket errors
+    /// out, when the client is too slow, or once every clone of the Outbound is gone and the queue is drained.
+    /// The client is too slow if the queue stays full or writing a frame takes longer than `send_timeout`.
+    /// The writer gets spawned on `tasks`, so whoever waits on them also waits for the last frames to go out.
+    pub fn spawn<S>(tasks: &TaskTracker, sink: S, capacity: usize, send_timeout: Duration) -> (Self, JoinHandle<()>)
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: Display + Send,
+    {
+        let (queue, receiver) = mpsc::channel(capacity);
+        let queue_full = Arc::new(Notify::new());
+        let writer = tasks.spawn(write_outbound(sink, receiver, queue_full.clone(), send_timeout).in_current_span());
+        (Self { queue, queue_full, send_timeout }, writer)
+    }
+
+    /// Queues the frame. If the queue is full, waits up to `send_timeout` for the writer to make room, so a burst
+    /// doesn't cost the client its socket. If there's still no room by then the client isn't keeping up, and the
+    /// writer closes the socket instead of letting frames pile up.
+    pub async fn send(&self, message: Message) -> Result<(), ChatError> {
+        let message = match self.queue.try_send(message) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(message)) => message,
+            Err(TrySendError::Closed(_)) => return Err(socket_closed()),
+        };
+        match self.queue.send_timeout(message, self.send_timeout).await {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => {
+                self.queue_full.notify_one();
+                Err(ChatError::Internal("Outbound queue stayed full, disconnecting slow consumer.".into()))
+            }
+            Err(SendTimeoutError::Closed(_)) => Err(socket_closed()),
+        }
+    }
+}
+
+fn socket_closed() -> ChatError {
+    ChatError::Internal("Socket is already closed.".into())
+}
+
+async fn write_outbound<S>(mut sink: S, mut queue: mpsc::Receiver<Message>, queue_full: Arc<Notify>, send_timeout: Duration)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: Display,
+{
+    loop {
+        let message = tokio::select! {
+            biased;
+            _ = queue_full.notified() => {
+                // Whatever is still queued gets dropped, the client wasn't going to read it anyway.
+                close_slow_consumer(&mut sink, "queue_full", send_timeout).await;
+                break;
+            }
+            message = queue.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+        let closing = matches!(message, Message::Close(_));
+        match timeout(send_timeout, sink.send(message)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                info!(%error, "Couldn't write to socket, closing it");
+                break;
+            }
+            Err(_) => {
+                close_slow_consumer(&mut sink, "send_timeout", send_timeout).await;
+                break;
+            }
+        }
+        if closing {
+            break;
+        }
+    }
+}
+
+/// Tries to say why before hanging up, but doesn't wait on the client any longer than for any other frame.
+async fn close_slow_consumer<S: Sink<Message> + Unpin>(sink: &mut S, reason: &'static str, send_timeout: Duration) {
+    info!(reason, "Disconnecting slow consumer");
+    metrics::increment_counter!(SLOW_CONSUMER_DISCONNECTS_METRIC, "reason" => reason);
+    let close_frame = CloseFrame { code: SLOW_CONSUMER_CLOSE_CODE, reason: "Slow consumer".into() };
+    let _ = timeout(send_timeout, sink.send(Message::Close(Some(close_frame)))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use axum::extract::ws::Message;
+    use futures::Sink;
+    use tokio::time::timeout;
+    use tokio_util::task::TaskTracker;
+
+    use super::{Outbound, SLOW_CONSUMER_DISCONNECTS_METRIC};
+    use crate::{net::utils::SLOW_CONSUMER_CLOSE_CODE, util::test_util::counter_value};
+
+    /// Takes every frame but never manages to flush one, like a client that stopped reading.
+    struct StalledSink {
+        sent: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Sink<Message> for StalledSink {
+        type Error = axum::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+            self.sent.lock().unwrap().push(message);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn clients_that_stop_reading_get_closed_as_slow_consumers() {
+        let disconnects = || counter_value(SLOW_CONSUMER_DISCONNECTS_METRIC, &[("reason", "send_timeout")]);
+        let disconnects_before = disconnects();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let (outbound, writer) =
+            Outbound::spawn(&TaskTracker::new(), StalledSink { sent: sent.clone() }, 2, Duration::from_millis(100));
+
+        // The writer gets stuck on the first frame and the other two fill the queue.
+        for frame in ["first", "second", "third"] {
+            outbound.send(Message::Text(frame.into())).await.unwrap();
+        }
+        timeout(Duration::from_secs(2), writer)
+            .await
+            .expect("The writer kept waiting on the client")
+            .unwrap();
+
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 2, "{sent:?}");
+            assert_eq!(sent[0], Message::Text("first".into()));
+            match &sent[1] {
+                Message::Close(Some(close_frame)) => assert_eq!(close_frame.code, SLOW_CONSUMER_CLOSE_CODE),
+                other => panic!("Expected a close frame, got {other:?}"),
+            }
+        }
+        assert!(outbound.send(Message::Text("too late".into())).await.is_err());
+        assert_eq!(disconnects(), disconnects_before + 1);
+    }
+}
@@ -1,38 +1,177 @@
-use std::sync::Arc;
+use axum::extract::ws::{CloseFrame, Message};
+use chat_types::dto::{message::ClientMessage, server_out::{ServerMessageOut, Sendable}, server_in::{ServerMessageIn, Receivable}};
+use serde_json::Value;
 
-use axum::extract::ws::{Message, WebSocket};
-use chat_types::{dto::{message::ClientMessage, server_out::{ServerMessageOut, Sendable}, server_in::{ServerMessageIn, Receivable}}, domain::error::SocketError};
-use futures::{stream::SplitSink, SinkExt};
-use tokio::sync::Mutex;
+use crate::domain::{chat_room_kick::ChatRoomKick, error::ChatError};
+
+use super::outbound::Outbound;
+
+/// The key clients can add to any frame (next to head and body) to tag it with an id of their choosing.
+/// Can be a string or a number, it gets echoed back untouched on every direct reply to that frame so that
+/// clients can have many requests in flight over the same socket and still match each reply to its request.
+pub const REQUEST_ID_KEY: &str = "requestId";
+/// The key under which error frames carry the stable error code.
+pub const ERROR_CODE_KEY: &str = "code";
+/// Application level ping, for clients behind proxies that strip websocket control frames.
+/// The server answers it with a `PONG_HEAD` frame.
+pub const PING_HEAD: &str = "PING";
+pub const PONG_HEAD: &str = "PONG";
+/// Pushed to a user when they get kicked out of a room, the body is the kick.
+pub const KICKED_HEAD: &str = "KICKED";
+/// Pushed to a user when an admin evicts one of their rooms, they stop getting its messages until they log in again.
+pub const ROOM_EVICTED_HEAD: &str = "ROOM EVICTED";
+/// Close code for sessions an admin kicked out.
+pub const SESSION_REVOKED_CLOSE_CODE: u16 = 4001;
+/// Close code for sessions whose credentials stopped being valid (expired or revoked token).
+pub const SESSION_EXPIRED_CLOSE_CODE: u16 = 4002;
+/// Close code for clients that don't read what they're sent fast enough.
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 4003;
+/// Close code for clients that kept going over their rate limits.
+pub const RATE_LIMITED_CLOSE_CODE: u16 = 4004;
+/// Close code for sockets an admin disconnected.
+pub const DISCONNECTED_BY_ADMIN_CLOSE_CODE: u16 = 4005;
+
+/// Everything a client can send through the socket. On top of the messages defined in chat_types
+/// the server also understands the application level ping.
+#[derive(Debug)]
+pub enum InboundFrame {
+    Ping,
+    Message(ServerMessageIn),
+}
 
 /// Este es el metodo para enviar mensajes a un cliente a traves de un websocket
 /// Si le pasas un None en el payload tienes que darle un tipo al metodo, ya que
 /// El compilador no permite especificarle un metodo default.
 pub async fn send_message(
-    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    sender: &Outbound,
+    message: ServerMessageOut,
+) -> Result<(), ChatError> {
+    send_frame(sender, message_to_frame(message)?).await
+}
+
+/// Sends a direct reply to a frame the client sent, echoing back the request id the frame was tagged with.
+/// Messages that are pushed to the client without it asking (broadcasts) go through `send_message` instead.
+pub async fn send_reply(
+    sender: &Outbound,
     message: ServerMessageOut,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    Ok(sender
-        .lock()
-        .await
-        .send(Message::Text(serde_json::to_string(
-            &message.into_message()?,
-        )?))
-        .await?)
+    request_id: &Option<Value>,
+) -> Result<(), ChatError> {
+    let mut frame = message_to_frame(message)?;
+    set_request_id(&mut frame, request_id);
+    send_frame(sender, frame).await
+}
+
+/// Sends a `ServerMessageOut::Error` with the error's code and the id of the request that failed (if the client sent one).
+pub async fn send_error(
+    sender: &Outbound,
+    error: &ChatError,
+    request_id: &Option<Value>,
+) -> Result<(), ChatError> {
+    let mut frame = message_to_frame(ServerMessageOut::Error(error.client_message().to_string()))?;
+    if let Value::Object(fields) = &mut frame {
+        fields.insert(ERROR_CODE_KEY.into(), Value::String(error.code().into()));
+    }
+    set_request_id(&mut frame, request_id);
+    send_frame(sender, frame).await
+}
+
+/// Answers an application level ping.
+pub async fn send_pong(
+    sender: &Outbound,
+    request_id: &Option<Value>,
+) -> Result<(), ChatError> {
+    let mut frame = serde_json::json!({ "head": PONG_HEAD });
+    set_request_id(&mut frame, request_id);
+    send_frame(sender, frame).await
+}
+
+/// Tells the user they got kicked out of a room, they won't get any more messages from it.
+pub async fn send_kicked(
+    sender: &Outbound,
+    kick: &ChatRoomKick,
+) -> Result<(), ChatError> {
+    send_frame(sender, serde_json::json!({ "head": KICKED_HEAD, "body": kick })).await
+}
+
+/// Tells the user one of their rooms got evicted, they won't get any more messages from it on this socket.
+pub async fn send_room_evicted(
+    sender: &Outbound,
+    chat_room_id: u32,
+) -> Result<(), ChatError> {
+    send_frame(sender, serde_json::json!({ "head": ROOM_EVICTED_HEAD, "body": { "chatRoomId": chat_room_id } })).await
+}
+
+/// Websocket level ping, the client's websocket implementation answers it with a pong on its own.
+pub async fn send_ping(sender: &Outbound) -> Result<(), ChatError> {
+    sender.send(Message::Ping(Vec::new())).await
+}
+
+/// Queues a close frame with the code and reason. The socket stops writing after it, the client should hang up.
+pub async fn close_connection(
+    sender: &Outbound,
+    code: u16,
+    reason: &'static str,
+) -> Result<(), ChatError> {
+    sender.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    })))
+    .await
+}
+
+fn set_request_id(frame: &mut Value, request_id: &Option<Value>) {
+    if let (Value::Object(fields), Some(request_id)) = (frame, request_id) {
+        fields.insert(REQUEST_ID_KEY.into(), request_id.clone());
+    }
+}
+
+fn message_to_frame(message: ServerMessageOut) -> Result<Value, ChatError> {
+    let client_message = message
+        .into_message()
+        .map_err(|error| ChatError::Internal(format!("Couldn't serialize server message: {error}")))?;
+    serde_json::to_value(client_message)
+        .map_err(|error| ChatError::Internal(format!("Couldn't serialize server message: {error}")))
+}
+
+async fn send_frame(
+    sender: &Outbound,
+    frame: Value,
+) -> Result<(), ChatError> {
+    sender.send(Message::Text(frame.to_string())).await
 }
 
 /// use this function to convert a Message::Text() from a client socket connection
-/// into a ClientMessage<Payload>
+/// into an InboundFrame. The request id is returned separately so that it can be sent
+/// back with the error even when the frame itself couldn't be interpreted.
 pub fn interpret_message(
     message: Message,
-) -> Result<ServerMessageIn, Box<dyn std::error::Error + Send + Sync>> {
-    if let Message::Text(txt) = message {
-        // txt should be a {"type": "SOMETHING"} or a {"type": "SOMETHING", "payload": {}}
-        let client_message: ClientMessage = serde_json::from_str(txt.as_str())?; //Add error message?
-        Ok(ServerMessageIn::from_message(client_message)?)
-    } else {
-        Err(SocketError::boxed_error(
-            "Recieved client Message is not of type Text...",
-        ))
+) -> (Option<Value>, Result<InboundFrame, ChatError>) {
+    let txt = match message {
+        Message::Text(txt) => txt,
+        _ => {
+            return (
+                None,
+                Err(ChatError::Validation(
+                    "Recieved client Message is not of type Text...".into(),
+                )),
+            )
+        }
+    };
+    // txt should be a {"head": "SOMETHING"} or a {"head": "SOMETHING", "body": {}} with an optional requestId
+    let frame: Value = match serde_json::from_str(txt.as_str()) {
+        Ok(frame) => frame,
+        Err(error) => return (None, Err(error.into())),
+    };
+    let request_id = frame.get(REQUEST_ID_KEY).cloned();
+    if frame.get("head").and_then(Value::as_str) == Some(PING_HEAD) {
+        return (request_id, Ok(InboundFrame::Ping));
     }
+    let client_message: ClientMessage = match serde_json::from_value(frame) {
+        Ok(client_message) => client_message,
+        Err(error) => return (request_id, Err(error.into())),
+    };
+    let server_message_in = ServerMessageIn::from_message(client_message)
+        .map(InboundFrame::Message)
+        .map_err(|error| ChatError::Validation(format!("Invalid message: {error}")));
+    (request_id, server_message_in)
 }
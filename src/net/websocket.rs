@@ -1,68 +1,413 @@
-use crate::{domain::state::AppState, net::handler::handle_message};
+use crate::{
+    domain::{chat_room_channel::RoomSubscriptions, error::ChatError, session::SessionEvent, state::AppState},
+    net::handler::{forward_room_message, handle_message},
+    service::{
+        auth::Credentials,
+        user::{is_addr_registered, register_authenticated_addr},
+    },
+};
 use axum::{
     extract::{
-        ws::{WebSocket, WebSocketUpgrade},
-        ConnectInfo, State,
+        ws::{close_code, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Query, State,
     },
-    response::{Html, IntoResponse},
-    routing::get,
-    Router,
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
 };
 use futures::stream::StreamExt;
-use sqlx::MySqlPool;
-use std::{net::SocketAddr, sync::Arc};
-
-use super::handler::disconnect_client;
-
-pub async fn start_ws_server(database_connection: MySqlPool, client: reqwest::Client) {
-    let app_state = Arc::new(AppState::new(database_connection, client));
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/websocket", get(websocket_handler))
-        .with_state(app_state);
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    println!("Finished setting up Socket server on port 3000.");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
-}
+use metrics::{counter, increment_counter};
+use std::{collections::HashMap, future::pending, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::UnboundedReceiver,
+    time::{interval, sleep, Instant},
+};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
 
-async fn websocket_handler(
+use super::{
+    handler::disconnect_client,
+    handshake::{credentials_from_handshake, CHAT_SUBPROTOCOL},
+    outbound::Outbound,
+    utils::{
+        close_connection, send_kicked, send_ping, send_room_evicted, DISCONNECTED_BY_ADMIN_CLOSE_CODE,
+        RATE_LIMITED_CLOSE_CODE, SESSION_EXPIRED_CLOSE_CODE, SESSION_REVOKED_CLOSE_CODE,
+    },
+};
+
+/// Times a socket fell behind on one of its rooms and the broadcast channel dropped messages for it.
+pub const BROADCAST_LAG_EVENTS_METRIC: &str = "chat_broadcast_lag_events_total";
+/// How many messages sockets missed because of it.
+pub const BROADCAST_LAGGED_MESSAGES_METRIC: &str = "chat_broadcast_lagged_messages_total";
+
+/// Window `max_rate_limit_violations_per_minute` gets counted over.
+const RATE_LIMIT_VIOLATIONS_WINDOW: Duration = Duration::from_secs(60);
+
+/// If the client sent credentials with the handshake they get checked before upgrading, so clients with bad
+/// credentials get a 401 and never get a socket. Clients without credentials can still log in through the socket
+/// unless `require_handshake_auth` is on.
+pub async fn websocket_handler(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    ws: WebSocketUpgrade,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| websocket(socket, state, addr))
+    ws: WebSocketUpgrade,
+) -> Response {
+    let authenticated_user = match credentials_from_handshake(&headers, &query) {
+        Some(Ok(credentials)) => match state.authenticator.authenticate(&credentials).await {
+            Ok(user) => Some((user.id, credentials)),
+            Err(error) => return unauthorized(error),
+        },
+        Some(Err(error)) => return unauthorized(error),
+        None if state.require_handshake_auth => {
+            return unauthorized(ChatError::AuthFailed(
+                "Credentials are required to open a socket.".into(),
+            ))
+        }
+        None => None,
+    };
+    ws.protocols([CHAT_SUBPROTOCOL])
+        .on_upgrade(move |socket| {
+            let tasks = state.tasks.clone();
+            // Everything logged while handling this socket, in this task or the ones it spawns, carries these.
+            let span = info_span!("connection", %addr, user_id = field::Empty);
+            tasks.track_future(websocket(socket, state, addr, authenticated_user).instrument(span))
+        })
 }
 
-async fn websocket(stream: WebSocket, state: Arc<AppState>, addr: SocketAddr) {
-    // By splitting we can send and receive at the same time.
-    let (sender, mut receiver) = stream.split();
-    let sender_reference = Arc::new(tokio::sync::Mutex::new(sender));
+fn unauthorized(error: ChatError) -> Response {
+    info!(%error, "Refusing websocket upgrade");
+    (StatusCode::UNAUTHORIZED, error.client_message().to_string()).into_response()
+}
+
+async fn websocket(
+    stream: WebSocket,
+    state: Arc<AppState>,
+    addr: SocketAddr,
+    authenticated_user: Option<(u32, Credentials)>,
+) {
+    // By splitting we can send and receive at the same time. Everything sent goes through the outbound queue,
+    // the writer task is the only one touching the sink.
+    let (sink, mut receiver) = stream.split();
+    let (sender, mut writer) = Outbound::spawn(&state.tasks, sink, state.outbound_queue_capacity, state.send_timeout);
 
-    let mut send_tasks = Vec::new();
+    let mut subscriptions = RoomSubscriptions::new();
+    let mut user_id = None;
 
-    // Loop until a text message is found.
-    while let Some(Ok(message)) = receiver.next().await {
-        match handle_message(message, sender_reference.clone(), state.clone(), addr, &mut send_tasks)
-            .await {
-                Ok(_) => {},
-                Err(error) => println!("Error recieved from handle message inside of the main websocket loop, Error: \n {}", error),
-            };
+    match authenticated_user {
+        Some((authenticated_user_id, credentials)) => {
+            if let Err(error) = register_authenticated_addr(state.clone(), &addr, &sender, authenticated_user_id, credentials, &None, &mut subscriptions).await {
+                warn!(%error, "Couldn't register socket authenticated during the handshake");
+                let _ = close_connection(&sender, close_code::ERROR, "Couldn't register socket").await;
+                return;
+            }
+            Span::current().record("user_id", authenticated_user_id);
+            user_id = Some(authenticated_user_id);
+        }
+        None => {
+            if let Err(error) = state.add_unauthenticated_client(addr.ip()) {
+                info!(%error, "Refusing socket");
+                let _ = close_connection(&sender, close_code::POLICY, "Too many unauthenticated connections").await;
+                return;
+            }
+        }
     }
-    match disconnect_client(&state, &addr, send_tasks).await {
-        Ok(_) => {
-            println!("Client disconnected.");
+
+    let mut heartbeat = interval(state.heartbeat_interval);
+    // The first tick completes right away, no need to ping a socket that just connected.
+    heartbeat.tick().await;
+    let mut last_seen = Instant::now();
+
+    let login_deadline = sleep(state.login_timeout);
+    tokio::pin!(login_deadline);
+    let mut failed_login_attempts = 0;
+    // Rate limited frames in the current one minute window, and when that window started.
+    let mut rate_limit_violations = 0;
+    let mut violations_window_start = Instant::now();
+
+    let mut revalidation = interval(state.session_revalidation_interval);
+    revalidation.tick().await;
+    let mut session_events = state.take_session_events(&addr);
+
+    // Loop until the client disconnects, stops answering pings, fails to log in, can't keep up with what it's sent
+    // or the server shuts down.
+    loop {
+        let logged_in = user_id.is_some();
+        tokio::select! {
+            message = receiver.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                // Any frame, pongs included, means the client is still there.
+                last_seen = Instant::now();
+                let result = handle_message(message, &sender, state.clone(), addr, &mut subscriptions).await;
+                match &result {
+                    Err(error @ ChatError::Internal(_)) => error!(%error, "Couldn't handle frame"),
+                    Err(error) => debug!(%error, "Frame rejected"),
+                    Ok(()) => {}
+                }
+                if let Err(ChatError::RateLimited(_)) = result {
+                    if violations_window_start.elapsed() >= RATE_LIMIT_VIOLATIONS_WINDOW {
+                        violations_window_start = Instant::now();
+                        rate_limit_violations = 0;
+                    }
+                    rate_limit_violations += 1;
+                    if rate_limit_violations > state.max_rate_limit_violations_per_minute {
+                        info!(rate_limit_violations, "Client kept going over its rate limits, disconnecting");
+                        let _ = close_connection(&sender, RATE_LIMITED_CLOSE_CODE, "Too many rate limited requests").await;
+                        break;
+                    }
+                }
+                if !logged_in {
+                    user_id = is_addr_registered(&state, &addr);
+                    if let Some(user_id) = user_id {
+                        Span::current().record("user_id", user_id);
+                        state.remove_unauthenticated_client(&addr.ip());
+                        session_events = state.take_session_events(&addr);
+                        revalidation.reset();
+                    } else if let Err(ChatError::AuthFailed(_)) = result {
+                        failed_login_attempts += 1;
+                        if failed_login_attempts >= state.max_login_attempts {
+                            info!(failed_login_attempts, "Client failed to log in too many times, disconnecting");
+                            let _ = close_connection(&sender, close_code::POLICY, "Too many failed login attempts").await;
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = &mut login_deadline, if !logged_in => {
+                info!("Client didn't log in on time, disconnecting");
+                let _ = close_connection(&sender, close_code::POLICY, "Login timeout").await;
+                break;
+            }
+            Some(event) = next_session_event(&mut session_events), if logged_in => match event {
+                SessionEvent::Revoked => {
+                    info!("Session was revoked, disconnecting");
+                    let _ = close_connection(&sender, SESSION_REVOKED_CLOSE_CODE, "Session revoked").await;
+                    break;
+                }
+                SessionEvent::Disconnected => {
+                    info!("Disconnected by an admin");
+                    let _ = close_connection(&sender, DISCONNECTED_BY_ADMIN_CLOSE_CODE, "Disconnected by an admin").await;
+                    break;
+                }
+                // Whichever instance the user left the room on, the instance the socket is on takes them out of it.
+                SessionEvent::Kicked(kick) => {
+                    state.remove_user_from_room(&kick.user_id, &kick.chat_room_id);
+                    subscriptions.remove(&kick.chat_room_id);
+                    if let Err(error) = send_kicked(&sender, &kick).await {
+                        warn!(%error, chat_room_id = kick.chat_room_id, "Couldn't tell client they got kicked");
+                    }
+                }
+                SessionEvent::LeftRoom(chat_room_id) => {
+                    if let Some(user_id) = user_id {
+                        state.remove_user_from_room(&user_id, &chat_room_id);
+                    }
+                    subscriptions.remove(&chat_room_id);
+                }
+                SessionEvent::RoomEvicted(chat_room_id) => {
+                    subscriptions.remove(&chat_room_id);
+                    if let Err(error) = send_room_evicted(&sender, chat_room_id).await {
+                        warn!(%error, chat_room_id, "Couldn't tell client the room got evicted");
+                    }
+                }
+            },
+            Some((chat_room_id, message)) = subscriptions.next(), if !subscriptions.is_empty() => match (user_id, message) {
+                (Some(user_id), Ok(message)) => {
+                    if let Err(error) = forward_room_message(&state, &sender, user_id, message).await {
+                        warn!(%error, chat_room_id, "Couldn't forward a message from chat room");
+                    }
+                }
+                (_, Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    warn!(skipped, chat_room_id, "Client fell behind and missed messages from chat room");
+                    increment_counter!(BROADCAST_LAG_EVENTS_METRIC);
+                    counter!(BROADCAST_LAGGED_MESSAGES_METRIC, skipped);
+                }
+                // Only logged in sockets have subscriptions.
+                (None, Ok(_)) => {}
+            },
+            _ = revalidation.tick(), if logged_in => {
+                let credentials = match state.get_session_credentials(&addr) {
+                    Some(credentials) => credentials,
+                    None => continue,
+                };
+                // Only a definitive rejection ends the session. If user-svc is down the socket stays and gets
+                // checked again on the next tick.
+                match state.authenticator.authenticate(&credentials).await {
+                    Ok(_) => {}
+                    Err(error @ ChatError::AuthFailed(_)) => {
+                        info!(%error, "Credentials aren't valid anymore, disconnecting");
+                        let _ = close_connection(&sender, SESSION_EXPIRED_CLOSE_CODE, "Session expired").await;
+                        break;
+                    }
+                    Err(error) => warn!(%error, "Couldn't revalidate credentials, keeping the session until the next check"),
+                }
+            }
+            _ = state.shutdown.cancelled() => {
+                let _ = close_connection(&sender, close_code::AWAY, "Server going away").await;
+                break;
+            }
+            // The writer stops on its own when the socket errors out or the client is too slow.
+            _ = &mut writer => {
+                info!("Stopped writing to client, disconnecting");
+                break;
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > state.heartbeat_timeout {
+                    info!("Client missed its heartbeat, disconnecting");
+                    let _ = close_connection(&sender, close_code::POLICY, "Heartbeat timeout").await;
+                    break;
+                }
+                if let Err(error) = send_ping(&sender).await {
+                    info!(%error, "Couldn't ping client, disconnecting");
+                    break;
+                }
+            }
         }
-        Err(error) => println!(
-            "Error recieved from disconnect_client inside of the main websocket loop, Error: \n {}",
-            error
-        ),
+    }
+    if user_id.is_none() {
+        state.remove_unauthenticated_client(&addr.ip());
+        info!("Unauthenticated client disconnected");
+        return;
+    }
+    match disconnect_client(&state, &addr).await {
+        Ok(_) => info!("Client disconnected"),
+        Err(error) => error!(%error, "Couldn't clean up after disconnected client"),
+    }
+}
+
+/// Never resolves if the socket has no session.
+async fn next_session_event(session_events: &mut Option<UnboundedReceiver<SessionEvent>>) -> Option<SessionEvent> {
+    match session_events {
+        Some(session_events) => session_events.recv().await,
+        None => pending().await,
     }
 }
 
 // Include utf-8 file at **compile** time.
-async fn index() -> Html<&'static str> {
+pub async fn index() -> Html<&'static str> {
     Html(std::include_str!("../chat.html"))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::extract::ws::close_code;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time::{sleep, timeout},
+    };
+
+    use sqlx::MySqlPool;
+    use tokio_tungstenite::connect_async;
+
+    use crate::util::test_util::{connect_anonymously, connect_as, lazy_pool, next_close, serve, test_state};
+
+    // Only the last one logs in, the others never touch the database.
+
+    const CLOSE_OPCODE: u8 = 0x8;
+    const PING_OPCODE: u8 = 0x9;
+
+    /// Splits what the server wrote after the upgrade into (opcode, payload). Server frames are never masked and
+    /// everything it sends in these tests is short.
+    fn server_frames(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while bytes.len() >= 2 {
+            let length = (bytes[1] & 0x7f) as usize;
+            assert!(length < 126, "Unexpectedly long frame");
+            frames.push((bytes[0] & 0x0f, bytes[2..2 + length].to_vec()));
+            bytes = &bytes[2 + length..];
+        }
+        frames
+    }
+
+    #[tokio::test]
+    async fn sockets_that_stop_answering_pings_get_reaped() {
+        let state = test_state(lazy_pool(), |config| {
+            config.websocket.heartbeat_interval_secs = 1;
+            config.websocket.heartbeat_timeout_secs = 2;
+            config.websocket.login_timeout_secs = 30;
+        });
+        let addr = serve(state);
+
+        // Client libraries answer pings on their own, so this one upgrades by hand and never writes again.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let handshake = format!(
+            "GET /websocket HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .expect("Timed out waiting for the server to hang up")
+            .unwrap();
+
+        let headers_end = received.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+        assert!(received.starts_with(b"HTTP/1.1 101"));
+        let frames = server_frames(&received[headers_end..]);
+        let pings = frames.iter().filter(|(opcode, _)| *opcode == PING_OPCODE).count();
+        assert!(pings >= 1, "The server never pinged");
+        let (opcode, close_payload) = frames.last().unwrap();
+        assert_eq!(*opcode, CLOSE_OPCODE);
+        assert_eq!(u16::from_be_bytes([close_payload[0], close_payload[1]]), close_code::POLICY);
+        assert_eq!(&close_payload[2..], b"Heartbeat timeout");
+    }
+
+    #[tokio::test]
+    async fn sockets_that_dont_log_in_on_time_get_closed() {
+        let state = test_state(lazy_pool(), |config| config.websocket.login_timeout_secs = 1);
+        let addr = serve(state);
+        let mut socket = connect_anonymously(addr).await;
+
+        let close_frame = next_close(&mut socket).await;
+        assert_eq!(u16::from(close_frame.code), close_code::POLICY);
+        assert_eq!(close_frame.reason, "Login timeout");
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_sockets_are_capped_per_ip() {
+        let state = test_state(lazy_pool(), |config| config.limits.max_unauthenticated_sockets_per_ip = 1);
+        let addr = serve(state.clone());
+        let first = connect_anonymously(addr).await;
+
+        let mut second = connect_anonymously(addr).await;
+        let close_frame = next_close(&mut second).await;
+        assert_eq!(u16::from(close_frame.code), close_code::POLICY);
+        assert_eq!(close_frame.reason, "Too many unauthenticated connections");
+
+        // Once the first one is gone its slot frees up.
+        drop(first);
+        timeout(Duration::from_secs(5), async {
+            while !state.unauthenticated_clients.is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Timed out waiting for the first socket to be cleaned up");
+        let mut third = connect_anonymously(addr).await;
+        assert!(timeout(Duration::from_millis(500), next_close(&mut third)).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn a_second_login_is_refused_without_touching_the_first(pool: MySqlPool) {
+        const USER_ID: u32 = 1;
+        let state = test_state(pool, |_| {});
+        let addr = serve(state.clone());
+        let _first = connect_as(&state, addr, USER_ID).await;
+
+        let (mut second, _) = connect_async(format!("ws://{addr}/websocket?user_id={USER_ID}&token=mock-{USER_ID}"))
+            .await
+            .unwrap();
+        let close_frame = next_close(&mut second).await;
+        assert_eq!(u16::from(close_frame.code), close_code::ERROR);
+
+        // The second socket cleaned up before closing. Only the first one is registered, and the user's rooms are
+        // still there for it.
+        assert_eq!(state.connected_clients.len(), 1);
+        assert_eq!(state.sessions.len(), 1);
+        assert!(state.user_rooms.contains_key(&USER_ID));
+    }
+}
@@ -1,81 +1,324 @@
-use actix_web::{
-    get, post,
-    web::{Data, Path, Json},
-    HttpRequest, delete,
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    Json,
 };
-use actix_web_utils::extensions::typed_response::TypedHttpResponse;
 use chat_types::{domain::{chat_room::ChatRoom, chat_user::ChatUser}, dto::chat::ChatRoomParticipants};
-use dev_macros::authenticate_route;
-use reqwest::Client;
-use sqlx::MySqlPool;
 
-use crate::service::http::chat_room_svc;
+use crate::{
+    domain::{
+        chat_room_kick::{ChatRoomKick, KickRequest},
+        error::ChatError,
+        state::AppState,
+    },
+    service::{auth::AuthenticatedUser, http::chat_room_svc},
+};
 
-#[get("/")]
 pub async fn get_all_user_chat_rooms(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-) -> TypedHttpResponse<Vec<ChatRoom>> {
-    let user = authenticate_route!(request, &client);
-    chat_room_svc::get_all_user_chat_rooms(&conn, &client, user, request).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+) -> Result<Json<Vec<ChatRoom>>, ChatError> {
+    chat_room_svc::get_all_user_chat_rooms(&state, user).await.map(Json)
 }
 
-#[post("/{title}")]
 pub async fn create_new_chat_room(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-    title: Path<String>,
-    participants: Json<ChatRoomParticipants>,    
-) -> TypedHttpResponse<ChatRoom> {
-    let user = authenticate_route!(request, &client);
-    
-    chat_room_svc::create_new_chat_room(&conn, &client, user, request, participants.0, title.to_string()).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(title): Path<String>,
+    Json(participants): Json<ChatRoomParticipants>,
+) -> Result<Json<ChatRoom>, ChatError> {
+    chat_room_svc::create_new_chat_room(&state, user, participants, title).await.map(Json)
 }
 
-#[post("/{chat_room_id}/participants")]
 pub async fn add_participants_to_chat_room(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-    chat_room_id: Path<u32>,
-    participants: Json<ChatRoomParticipants>,
-) -> TypedHttpResponse<ChatRoomParticipants> {
-    let user = authenticate_route!(request, &client);
-    chat_room_svc::add_participants_to_chat_room(&conn, &client, user, request, participants.0, *chat_room_id).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(chat_room_id): Path<u32>,
+    Json(participants): Json<ChatRoomParticipants>,
+) -> Result<Json<ChatRoomParticipants>, ChatError> {
+    chat_room_svc::add_participants_to_chat_room(&state, user, participants, chat_room_id).await.map(Json)
 }
 
-#[get("/{chat_room_id}/participants")]
 pub async fn get_chat_room_participants(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-    chat_room_id: Path<u32>,
-) -> TypedHttpResponse<Vec<ChatUser>> {
-    let user = authenticate_route!(request, &client);
-    chat_room_svc::get_chat_room_participants(&conn, &client, user, request, *chat_room_id).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(chat_room_id): Path<u32>,
+) -> Result<Json<Vec<ChatUser>>, ChatError> {
+    chat_room_svc::get_chat_room_participants(&state, user, chat_room_id).await.map(Json)
 }
 
-#[delete("/{chat_room_id}/leave")]
 pub async fn leave_chat_room(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-    chat_room_id: Path<u32>,
-) -> TypedHttpResponse<ChatUser> {
-    let user = authenticate_route!(request, &client);
-    chat_room_svc::leave_chat_room(&conn, &client, user, request, *chat_room_id).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path(chat_room_id): Path<u32>,
+) -> Result<(), ChatError> {
+    chat_room_svc::leave_chat_room(&state, user, chat_room_id).await
 }
 
-#[delete("/{chat_room_id}/kick/{user_id}")]
 pub async fn kick_user_from_chat_room(
-    conn: Data<MySqlPool>,
-    client: Data<Client>,
-    request: HttpRequest,
-    path_vars: Path<(u32, u32)>,
-) -> TypedHttpResponse<ChatUser> {
-    let user = authenticate_route!(request, &client);
-    chat_room_svc::kick_user_from_chat_room(&conn, &client, user, request, path_vars.0, path_vars.1).await
+    State(state): State<Arc<AppState>>,
+    user: AuthenticatedUser,
+    Path((chat_room_id, user_id)): Path<(u32, u32)>,
+    body: Bytes,
+) -> Result<Json<ChatRoomKick>, ChatError> {
+    let kick_request = kick_request_from_body(&body)?;
+    chat_room_svc::kick_user_from_chat_room(&state, user, chat_room_id, user_id, kick_request).await.map(Json)
+}
+
+/// The body is optional, no body at all is a kick without a reason or a ban. Anything else has to be a valid
+/// KickRequest, a ban that can't be read must not turn into a kick without one.
+fn kick_request_from_body(body: &[u8]) -> Result<KickRequest, ChatError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(KickRequest::default());
+    }
+    serde_json::from_slice(body).map_err(|error| ChatError::Validation(format!("Invalid kick body: {error}")))
 }
 
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use chrono::{Duration, Utc};
+    use serde_json::{json, Value};
+    use sqlx::MySqlPool;
+
+    use crate::util::test_util::{
+        connect_as, create_room, next_frame_with_head, request_as, serve, test_router, test_state,
+    };
+
+    const OWNER_ID: u32 = 1;
+    const MEMBER_ID: u32 = 2;
+    const OUTSIDER_ID: u32 = 3;
+    const ADMIN_ID: u32 = 4;
+
+    fn assert_not_a_member((status, error): (StatusCode, Value)) {
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error["code"], "NOT_A_MEMBER");
+    }
+
+    #[sqlx::test]
+    async fn non_member_cant_see_participants(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        assert_not_a_member(request_as(&router, OUTSIDER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn non_member_cant_add_participants(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let response = request_as(
+            &router,
+            OUTSIDER_ID,
+            Method::POST,
+            &format!("/chat/room/{room_id}/participants"),
+            Some(json!({ "participants": [OUTSIDER_ID] })),
+        )
+        .await;
+        assert_not_a_member(response);
+    }
+
+    #[sqlx::test]
+    async fn non_member_cant_leave(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        assert_not_a_member(request_as(&router, OUTSIDER_ID, Method::DELETE, &format!("/chat/room/{room_id}/leave"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn non_member_cant_kick(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        assert_not_a_member(request_as(&router, OUTSIDER_ID, Method::DELETE, &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn non_member_doesnt_see_the_room_in_their_list(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, chat_rooms) = request_as(&router, OUTSIDER_ID, Method::GET, "/chat/room", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(chat_rooms.as_array().unwrap().iter().all(|chat_room| chat_room["id"] != room_id));
+    }
+
+    #[sqlx::test]
+    async fn rooms_that_dont_exist_look_the_same_as_rooms_the_user_isnt_in(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        assert_not_a_member(request_as(&router, OUTSIDER_ID, Method::GET, &format!("/chat/room/{}/participants", u32::MAX), None).await);
+    }
+
+    #[sqlx::test]
+    async fn members_can_see_participants(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        for user_id in [OWNER_ID, MEMBER_ID] {
+            let (status, participants) = request_as(&router, user_id, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(participants.as_array().unwrap().len(), 2);
+        }
+    }
+
+    #[sqlx::test]
+    async fn members_that_dont_own_the_room_cant_add_participants(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, error) = request_as(
+            &router,
+            MEMBER_ID,
+            Method::POST,
+            &format!("/chat/room/{room_id}/participants"),
+            Some(json!({ "participants": [OUTSIDER_ID] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error["code"], "FORBIDDEN");
+    }
+
+    #[sqlx::test]
+    async fn adding_no_participants_is_rejected(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, error) = request_as(
+            &router,
+            OWNER_ID,
+            Method::POST,
+            &format!("/chat/room/{room_id}/participants"),
+            Some(json!({ "participants": [] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "VALIDATION");
+    }
+
+    #[sqlx::test]
+    async fn admin_can_see_participants_of_rooms_they_arent_in(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |config| config.auth.admin_user_ids = vec![ADMIN_ID]));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, participants) = request_as(&router, ADMIN_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(participants.as_array().unwrap().len(), 2);
+    }
+
+    #[sqlx::test]
+    async fn admin_gets_not_found_for_rooms_that_dont_exist(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |config| config.auth.admin_user_ids = vec![ADMIN_ID]));
+        let (status, error) = request_as(&router, ADMIN_ID, Method::GET, &format!("/chat/room/{}/participants", u32::MAX), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["code"], "NOT_FOUND");
+    }
+
+    #[sqlx::test]
+    async fn admin_can_add_participants_to_rooms_they_dont_own(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |config| config.auth.admin_user_ids = vec![ADMIN_ID]));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, _) = request_as(
+            &router,
+            ADMIN_ID,
+            Method::POST,
+            &format!("/chat/room/{room_id}/participants"),
+            Some(json!({ "participants": [OUTSIDER_ID] })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, participants) = request_as(&router, OUTSIDER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await;
+        assert_eq!(participants.as_array().unwrap().len(), 3);
+    }
+
+    #[sqlx::test]
+    async fn admin_can_kick_from_rooms_they_arent_in(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |config| config.auth.admin_user_ids = vec![ADMIN_ID]));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, _) = request_as(&router, ADMIN_ID, Method::DELETE, &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_not_a_member(request_as(&router, MEMBER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn admin_cant_leave_rooms_they_arent_in(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |config| config.auth.admin_user_ids = vec![ADMIN_ID]));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        assert_not_a_member(request_as(&router, ADMIN_ID, Method::DELETE, &format!("/chat/room/{room_id}/leave"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn kick_removes_the_target_and_not_the_kicker(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, kick) = request_as(&router, OWNER_ID, Method::DELETE, &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(kick["userId"], MEMBER_ID);
+        assert_eq!(kick["kickedBy"], OWNER_ID);
+        let (status, participants) = request_as(&router, OWNER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(participants.as_array().unwrap().len(), 1);
+        assert_not_a_member(request_as(&router, MEMBER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await);
+    }
+
+    #[sqlx::test]
+    async fn kick_with_an_invalid_body_is_rejected(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let (status, error) = request_as(
+            &router,
+            OWNER_ID,
+            Method::DELETE,
+            &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"),
+            Some(json!({ "bannedUntil": "next tuesday" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error["code"], "VALIDATION");
+        let (status, _) = request_as(&router, MEMBER_ID, Method::GET, &format!("/chat/room/{room_id}/participants"), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn banned_users_cant_be_added_back_until_the_ban_is_over(pool: MySqlPool) {
+        let router = test_router(test_state(pool, |_| {}));
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID, OUTSIDER_ID]).await;
+        let banned_until = Utc::now() + Duration::hours(1);
+        let (status, _) = request_as(
+            &router,
+            OWNER_ID,
+            Method::DELETE,
+            &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"),
+            Some(json!({ "reason": "spam", "bannedUntil": banned_until })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request_as(&router, OWNER_ID, Method::DELETE, &format!("/chat/room/{room_id}/kick/{OUTSIDER_ID}"), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let participants_path = format!("/chat/room/{room_id}/participants");
+        let add = |user_id: u32| request_as(&router, OWNER_ID, Method::POST, &participants_path, Some(json!({ "participants": [user_id] })));
+        let (status, error) = add(MEMBER_ID).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(error["code"], "FORBIDDEN");
+        let (status, _) = add(OUTSIDER_ID).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn connected_users_get_a_kicked_frame(pool: MySqlPool) {
+        let state = test_state(pool, |_| {});
+        let router = test_router(state.clone());
+        let addr = serve(state.clone());
+        let room_id = create_room(&router, OWNER_ID, &[MEMBER_ID]).await;
+        let mut socket = connect_as(&state, addr, MEMBER_ID).await;
+
+        let (status, _) = request_as(
+            &router,
+            OWNER_ID,
+            Method::DELETE,
+            &format!("/chat/room/{room_id}/kick/{MEMBER_ID}"),
+            Some(json!({ "reason": "spam" })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let frame = next_frame_with_head(&mut socket, "KICKED").await;
+        assert_eq!(frame["body"]["chatRoomId"], room_id);
+        assert_eq!(frame["body"]["userId"], MEMBER_ID);
+        assert_eq!(frame["body"]["reason"], "spam");
+    }
+}
@@ -1,36 +1,150 @@
-use actix_cors::Cors;
-use actix_web::{web, App, HttpServer};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
 use sqlx::MySqlPool;
+use tower_http::cors::CorsLayer;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    domain::state::AppState,
+    net::{
+        admin::{
+            disconnect_user, evict_room, get_active_rooms, get_connected_users, get_message_update_queue,
+            revoke_user_sessions,
+        },
+        health::{healthz, readyz},
+        metrics::{self, track_http_duration},
+        websocket::{index, websocket_handler},
+    },
+    routes::http::chat_room::{get_all_user_chat_rooms, create_new_chat_room, add_participants_to_chat_room, get_chat_room_participants, leave_chat_room, kick_user_from_chat_room},
+    service::{auth::Authenticator, fanout::Fanout},
+};
 
-use crate::{routes::http::chat_room::{get_all_user_chat_rooms, create_new_chat_room, add_participants_to_chat_room, get_chat_room_participants, leave_chat_room, kick_user_from_chat_room}};
+/// How often rate limit buckets of users and rooms that went quiet get dropped.
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
 
-pub async fn start_http_server(
+/// Sockets, REST routes and admin routes all get served from the same port and share the same AppState,
+/// so HTTP handlers can reach the live sessions. Returns once a SIGTERM or SIGINT was handled: new connections
+/// stop being accepted, every socket gets a going away close frame, in-flight requests, sockets and the updates
+/// they spawned get `shutdown_timeout_secs` between all of them to finish and then the database pool is closed.
+pub async fn start_server(
+    config: &Config,
     database_connection: MySqlPool,
     client: reqwest::Client,
-) -> Result<(), std::io::Error> {
-    let db_state = web::Data::new(database_connection.clone());
-    let client_state = web::Data::new(client);
-    let server_future = HttpServer::new(move || {
-        let cors_policy = Cors::permissive();
-        App::new()
-            .wrap(cors_policy)
-            //  Define routes & pass in shared state
-            .app_data(db_state.clone())
-            .app_data(client_state.clone())
-            .service(
-                web::scope("/chat")
-                    .service(web::scope("/room")
-                        .service(get_all_user_chat_rooms)
-                        .service(create_new_chat_room)
-                        .service(add_participants_to_chat_room)
-                        .service(get_chat_room_participants)
-                        .service(leave_chat_room)
-                        .service(kick_user_from_chat_room))
-                    .service(
-                        web::scope("/messages"), //    .service()
-                    ),
-            )
+    authenticator: Arc<dyn Authenticator>,
+    fanout: Arc<dyn Fanout>,
+    prometheus: PrometheusHandle,
+) -> Result<(), hyper::Error> {
+    let app_state = Arc::new(AppState::new(database_connection, client, authenticator, fanout, config));
+    app_state.fanout.start(app_state.clone());
+    let pruned_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut prune = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+        loop {
+            prune.tick().await;
+            pruned_state.rate_limits.prune();
+        }
     });
-    println!("Finished HTTP server setup on port 8082.");
-    return server_future.bind(("0.0.0.0", 8082))?.run().await;
+    let app = router(app_state.clone(), prometheus);
+    info!(listen_addr = %config.server.listen_addr, "Finished server setup");
+    let shutdown = app_state.shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down, closing every socket");
+        shutdown.cancel();
+    });
+    let shutdown = app_state.shutdown.clone();
+    let server = axum::Server::bind(&config.server.listen_addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
+    tokio::pin!(server);
+    tokio::select! {
+        served = &mut server => served?,
+        _ = app_state.shutdown.cancelled() => {}
+    }
+
+    // In-flight requests, sockets and the updates they spawned all share the one deadline.
+    app_state.tasks.close();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    let drain = async {
+        let served = server.await;
+        app_state.tasks.wait().await;
+        served
+    };
+    let served = match tokio::time::timeout(shutdown_timeout, drain).await {
+        Ok(served) => served,
+        Err(_) => {
+            warn!(
+                unfinished_tasks = app_state.tasks.len(),
+                shutdown_timeout_secs = shutdown_timeout.as_secs(),
+                "Requests, sockets or message updates didn't finish on time, dropping them"
+            );
+            Ok(())
+        }
+    };
+    app_state.db_conn.close().await;
+    served?;
+    info!("Shut down cleanly");
+    Ok(())
+}
+
+/// Every route the server answers, sockets included.
+pub fn router(app_state: Arc<AppState>, prometheus: PrometheusHandle) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/websocket", get(websocket_handler))
+        .route("/admin/users", get(get_connected_users))
+        .route("/admin/users/:user_id/sessions", delete(revoke_user_sessions))
+        .route("/admin/users/:user_id/connections", delete(disconnect_user))
+        .route("/admin/rooms", get(get_active_rooms))
+        .route("/admin/rooms/:room_id", delete(evict_room))
+        .route("/admin/message-update-queue", get(get_message_update_queue))
+        //  Chat room routes. Path params at the same position must share a name, hence :room for the title too.
+        .route("/chat/room", get(get_all_user_chat_rooms))
+        .route("/chat/room/", get(get_all_user_chat_rooms))
+        .route("/chat/room/:room", post(create_new_chat_room))
+        .route("/chat/room/:room/participants", post(add_participants_to_chat_room).get(get_chat_room_participants))
+        .route("/chat/room/:room/leave", delete(leave_chat_room))
+        .route("/chat/room/:room/kick/:user_id", delete(kick_user_from_chat_room))
+        .route("/metrics", get(metrics::metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route_layer(middleware::from_fn(track_http_duration))
+        .layer(Extension(prometheus))
+        .layer(CorsLayer::permissive())
+        .with_state(app_state)
+}
+
+/// Resolves on the first SIGINT (ctrl-c) or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            error!(%error, "Couldn't listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(error) => {
+                error!(%error, "Couldn't listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
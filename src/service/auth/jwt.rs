@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::domain::error::ChatError;
+
+use super::{AuthenticatedUser, Authenticator, Credentials};
+
+/// Verifies JWTs locally, so authenticating doesn't need user-svc to be up. The user id is the `sub` claim.
+pub struct JwtAuthenticator {
+    keys: JwtKeys,
+    validation: Validation,
+}
+
+enum JwtKeys {
+    /// Shared secret, tokens are signed with HS256.
+    Secret(DecodingKey),
+    /// Public keys fetched from the issuer on startup, picked by the `kid` in the token's header.
+    Jwks(JwkSet),
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: Value,
+}
+
+impl std::fmt::Debug for JwtAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys = match &self.keys {
+            JwtKeys::Secret(_) => "secret".to_string(),
+            JwtKeys::Jwks(jwks) => format!("{} keys from JWKS", jwks.keys.len()),
+        };
+        f.debug_struct("JwtAuthenticator").field("keys", &keys).finish()
+    }
+}
+
+impl JwtAuthenticator {
+    pub fn with_secret(secret: &str, validation: Validation) -> Self {
+        Self {
+            keys: JwtKeys::Secret(DecodingKey::from_secret(secret.as_bytes())),
+            validation,
+        }
+    }
+
+    pub async fn with_jwks(
+        client: &reqwest::Client,
+        jwks_url: &str,
+        validation: Validation,
+    ) -> Result<Self, reqwest::Error> {
+        let jwks = client.get(jwks_url).send().await?.error_for_status()?.json().await?;
+        Ok(Self {
+            keys: JwtKeys::Jwks(jwks),
+            validation,
+        })
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<AuthenticatedUser, ChatError> {
+        let invalid_token = |error: jsonwebtoken::errors::Error| ChatError::AuthFailed(format!("Invalid token: {error}"));
+        let mut validation = self.validation.clone();
+        let jwks_key;
+        let key = match &self.keys {
+            JwtKeys::Secret(key) => key,
+            JwtKeys::Jwks(jwks) => {
+                let header = decode_header(&credentials.token).map_err(invalid_token)?;
+                let jwk = header
+                    .kid
+                    .as_ref()
+                    .and_then(|kid| jwks.find(kid))
+                    .ok_or_else(|| ChatError::AuthFailed("Token signed with an unknown key.".into()))?;
+                validation.algorithms = vec![jwk.common.algorithm.unwrap_or(header.alg)];
+                jwks_key = DecodingKey::from_jwk(jwk).map_err(invalid_token)?;
+                &jwks_key
+            }
+        };
+        let claims = decode::<Claims>(&credentials.token, key, &validation)
+            .map_err(invalid_token)?
+            .claims;
+        // sub is usually a string, but some issuers put the number in as is.
+        let user_id: u32 = match &claims.sub {
+            Value::String(sub) => sub.parse().ok(),
+            Value::Number(sub) => sub.as_u64().and_then(|sub| sub.try_into().ok()),
+            _ => None,
+        }
+        .ok_or_else(|| ChatError::AuthFailed("Token's sub claim isn't a user id.".into()))?;
+        if let Some(claimed_user_id) = &credentials.user_id {
+            if claimed_user_id != &user_id.to_string() {
+                return Err(ChatError::AuthFailed("Token belongs to another user.".into()));
+            }
+        }
+        Ok(AuthenticatedUser { id: user_id })
+    }
+}
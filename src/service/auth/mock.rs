@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+
+use crate::domain::error::ChatError;
+
+use super::{AuthenticatedUser, Authenticator, Credentials};
+
+/// Only for tests and local development. A token that looks like `mock-<user_id>` authenticates as that user,
+/// anything else is rejected. Lets the integration tests log in as whoever they want without user-svc.
+#[derive(Debug, Default)]
+pub struct MockAuthenticator;
+
+#[async_trait]
+impl Authenticator for MockAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<AuthenticatedUser, ChatError> {
+        let user_id: u32 = credentials
+            .token
+            .strip_prefix("mock-")
+            .and_then(|user_id| user_id.parse().ok())
+            .ok_or_else(|| ChatError::AuthFailed("Mock tokens look like mock-<user_id>.".into()))?;
+        if let Some(claimed_user_id) = &credentials.user_id {
+            if claimed_user_id != &user_id.to_string() {
+                return Err(ChatError::AuthFailed("Token belongs to another user.".into()));
+            }
+        }
+        Ok(AuthenticatedUser { id: user_id })
+    }
+}
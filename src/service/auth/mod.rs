@@ -0,0 +1,125 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap},
+};
+use jsonwebtoken::Validation;
+use tracing::warn;
+
+use crate::{
+    config::{AuthConfig, AuthMode},
+    domain::{error::ChatError, state::AppState},
+};
+
+use self::{jwt::JwtAuthenticator, mock::MockAuthenticator, remote::RemoteAuthenticator};
+
+pub mod jwt;
+pub mod mock;
+pub mod remote;
+
+/// The user the credentials belong to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthenticatedUser {
+    pub id: u32,
+}
+
+/// What a client sends to prove who they are. Tokens issued by user-svc are only valid together with the user id,
+/// JWTs carry the user id inside of them so it's optional for those.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Credentials {
+    pub user_id: Option<String>,
+    pub token: String,
+}
+
+impl Credentials {
+    /// Parses the value of a bearer token, either `<user_id>:<token>` or a bare token (JWTs never contain a colon).
+    pub fn from_bearer(bearer: &str) -> Self {
+        match bearer.split_once(':') {
+            Some((user_id, token)) => Self {
+                user_id: Some(user_id.to_string()),
+                token: token.to_string(),
+            },
+            None => Self {
+                user_id: None,
+                token: bearer.to_string(),
+            },
+        }
+    }
+
+    /// HTTP clients send their credentials in the `Authorization: Bearer ...` header.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ChatError> {
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|authorization| authorization.to_str().ok())
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(Self::from_bearer)
+            .ok_or_else(|| ChatError::AuthFailed("Missing Authorization: Bearer header.".into()))
+    }
+}
+
+/// Checks credentials and figures out which user they belong to. Both the HTTP routes and the websocket logins
+/// go through the same authenticator, which one is used is decided on startup by `auth.mode`.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<AuthenticatedUser, ChatError>;
+
+    /// Whether logins can be expected to work right now, checked by `/readyz`. The error says why not.
+    async fn ready(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Builds the authenticator for the configured auth mode:
+/// - `remote`: every token gets checked against user-svc, valid tokens are cached for `cache_ttl_secs`.
+/// - `jwt`: tokens are JWTs verified locally, with `jwt_secret` (HS256) or with the keys published at `jwks_url`.
+///   `jwt_issuer` and `jwt_audience` are checked if set.
+/// - `mock`: for tests only, see MockAuthenticator.
+pub async fn authenticator_from_config(
+    config: &AuthConfig,
+    client: reqwest::Client,
+) -> Result<Arc<dyn Authenticator>, String> {
+    match config.mode {
+        AuthMode::Remote => Ok(Arc::new(RemoteAuthenticator::new(
+            client,
+            config.user_svc_url.clone(),
+            Duration::from_secs(config.cache_ttl_secs),
+        ))),
+        AuthMode::Jwt => {
+            let mut validation = Validation::default();
+            if let Some(issuer) = &config.jwt_issuer {
+                validation.set_issuer(&[issuer]);
+            }
+            if let Some(audience) = &config.jwt_audience {
+                validation.set_audience(&[audience]);
+            }
+            match (&config.jwt_secret, &config.jwks_url) {
+                (Some(secret), _) => Ok(Arc::new(JwtAuthenticator::with_secret(secret, validation))),
+                (None, Some(jwks_url)) => match JwtAuthenticator::with_jwks(&client, jwks_url, validation).await {
+                    Ok(authenticator) => Ok(Arc::new(authenticator)),
+                    Err(error) => Err(format!("Couldn't load the JWKS from {jwks_url}: {error}")),
+                },
+                (None, None) => Err("auth.mode = jwt needs either jwt_secret or jwks_url".to_string()),
+            }
+        }
+        AuthMode::Mock => {
+            warn!("Auth mode is mock, anyone can log in as anyone. Never use this outside of tests.");
+            Ok(Arc::new(MockAuthenticator))
+        }
+    }
+}
+
+/// Lets HTTP handlers take an `AuthenticatedUser` argument, requests that can't be authenticated get a 401.
+/// Every chat room route goes through here, so this is also where the per user HTTP rate limit gets applied.
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthenticatedUser {
+    type Rejection = ChatError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let credentials = Credentials::from_headers(&parts.headers)?;
+        let user = state.authenticator.authenticate(&credentials).await?;
+        state.rate_limits.check_http(user.id)?;
+        Ok(user)
+    }
+}
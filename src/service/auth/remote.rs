@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chat_types::domain::error::MUTEX_LOCK_ERROR_MESSAGE;
+use dev_communicators::middleware::user_svc::user_service;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+use crate::domain::error::ChatError;
+
+use super::{AuthenticatedUser, Authenticator, Credentials};
+
+/// Once the cache gets this big, expired entries get dropped before inserting new ones.
+const MAX_CACHED_TOKENS: usize = 10_000;
+/// How long `ready` waits on user-svc.
+const READINESS_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks every token against user-svc. Valid tokens are remembered for a short while so that a client
+/// doing a bunch of requests in a row doesn't cost a round trip to user-svc each time.
+#[derive(Debug)]
+pub struct RemoteAuthenticator {
+    client: reqwest::Client,
+    /// Overrides the endpoint dev-communicators talks to. Gets the same body and must answer with the user.
+    user_svc_url: Option<String>,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<Credentials, (AuthenticatedUser, Instant)>>,
+}
+
+impl RemoteAuthenticator {
+    pub fn new(client: reqwest::Client, user_svc_url: Option<String>, cache_ttl: Duration) -> Self {
+        Self {
+            client,
+            user_svc_url,
+            cache_ttl,
+            cache: Default::default(),
+        }
+    }
+
+    fn get_cached(&self, credentials: &Credentials) -> Option<AuthenticatedUser> {
+        let mut cache = self.cache.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
+        match cache.get(credentials) {
+            Some((user, validated_at)) if validated_at.elapsed() < self.cache_ttl => Some(*user),
+            Some(_) => {
+                cache.remove(credentials);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn authenticate_with(&self, user_svc_url: &str, user_id: &str, token: &str) -> Result<i64, ChatError> {
+        let persisted_user: Value = self
+            .client
+            .post(user_svc_url)
+            .json(&json!({ "id": user_id, "token": token }))
+            .send()
+            .await
+            .map_err(user_svc_error)?
+            .error_for_status()
+            .map_err(user_svc_error)?
+            .json()
+            .await
+            .map_err(user_svc_error)?;
+        persisted_user
+            .get("id")
+            .and_then(Value::as_i64)
+            .ok_or_else(|| ChatError::Internal("user-svc answered without the user's id.".into()))
+    }
+
+    fn is_cache_warm(&self) -> bool {
+        let cache = self.cache.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
+        cache.values().any(|(_, validated_at)| validated_at.elapsed() < self.cache_ttl)
+    }
+
+    fn cache(&self, credentials: &Credentials, user: AuthenticatedUser) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().expect(MUTEX_LOCK_ERROR_MESSAGE);
+        if cache.len() >= MAX_CACHED_TOKENS {
+            cache.retain(|_, (_, validated_at)| validated_at.elapsed() < self.cache_ttl);
+            if cache.len() >= MAX_CACHED_TOKENS {
+                cache.clear();
+            }
+        }
+        cache.insert(credentials.clone(), (user, Instant::now()));
+    }
+}
+
+/// Only user-svc saying no is an `AuthFailed`. Not reaching it, timeouts, 5xx and answers that can't be read
+/// are on our side and might work on the next try, so they must not log anyone out.
+fn user_svc_error(error: reqwest::Error) -> ChatError {
+    match error.status() {
+        Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND) => {
+            ChatError::AuthFailed(format!("Couldn't authenticate user: {error}"))
+        }
+        _ => ChatError::Internal(format!("Couldn't reach user-svc: {error}")),
+    }
+}
+
+#[async_trait]
+impl Authenticator for RemoteAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<AuthenticatedUser, ChatError> {
+        if let Some(user) = self.get_cached(credentials) {
+            return Ok(user);
+        }
+        let user_id = match &credentials.user_id {
+            Some(user_id) => user_id,
+            None => {
+                return Err(ChatError::AuthFailed(
+                    "user-svc tokens need the user id next to them: <user_id>:<token>".into(),
+                ))
+            }
+        };
+        let persisted_user_id = match &self.user_svc_url {
+            Some(user_svc_url) => self.authenticate_with(user_svc_url, user_id, &credentials.token).await?,
+            None => {
+                // Same body the clients send in band when logging in.
+                let user_for_auth =
+                    serde_json::from_value(json!({ "id": user_id, "token": credentials.token }))?;
+                user_service::authenticate_user_with_token(&self.client, &user_for_auth)
+                    .await
+                    .map_err(|error| match error.downcast::<reqwest::Error>() {
+                        Ok(error) => user_svc_error(*error),
+                        // Anything that isn't an HTTP error is dev-communicators reading user-svc's answer.
+                        Err(error) => ChatError::AuthFailed(format!("Couldn't authenticate user: {error}")),
+                    })?
+                    .id
+                    .into()
+            }
+        };
+        let user = AuthenticatedUser {
+            id: persisted_user_id
+                .try_into()
+                .map_err(|_| ChatError::Internal(format!("Invalid user id: {persisted_user_id}")))?,
+        };
+        self.cache(credentials, user);
+        Ok(user)
+    }
+
+    /// Ready if users logged in recently (their tokens are still cached) or if user-svc answers at all, any
+    /// status code counts. Without `user_svc_url` there's no endpoint to probe, so only the cache gets checked
+    /// and a cold cache doesn't hold readiness back.
+    async fn ready(&self) -> Result<(), String> {
+        if self.is_cache_warm() {
+            return Ok(());
+        }
+        let user_svc_url = match &self.user_svc_url {
+            Some(user_svc_url) => user_svc_url,
+            None => return Ok(()),
+        };
+        match self.client.head(user_svc_url).timeout(READINESS_PROBE_TIMEOUT).send().await {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("user-svc isn't reachable: {error}")),
+        }
+    }
+}
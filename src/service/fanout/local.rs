@@ -0,0 +1,24 @@
+use async_trait::async_trait;
+use chat_types::domain::chat_message::BroadcastMessage;
+
+use crate::domain::{error::ChatError, session::SessionEvent, state::AppState};
+
+use super::Fanout;
+
+/// Sends straight to the room's broadcast channel. Only reaches sockets connected to this instance, so it's only
+/// good for running a single instance.
+#[derive(Debug)]
+pub struct LocalFanout;
+
+#[async_trait]
+impl Fanout for LocalFanout {
+    async fn publish(&self, state: &AppState, chat_room_id: u32, message: BroadcastMessage) -> Result<(), ChatError> {
+        state.broadcast_to_local_subscribers(&chat_room_id, message)?;
+        Ok(())
+    }
+
+    async fn publish_session_event(&self, state: &AppState, user_id: u32, event: SessionEvent) -> Result<(), ChatError> {
+        state.send_session_event(&user_id, event);
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use chat_types::domain::chat_message::BroadcastMessage;
+
+use crate::{
+    config::{FanoutConfig, FanoutMode},
+    domain::{error::ChatError, session::SessionEvent, state::AppState},
+};
+
+use self::{local::LocalFanout, redis::RedisFanout};
+
+pub mod local;
+pub mod redis;
+
+/// Gets messages to every socket subscribed to a room. Sockets always listen on their room's `ChatRoomChannel`
+/// (`recipient_sockets`) on the instance they're connected to, the fanout decides how a published message gets
+/// to those channels, just on this instance or on every instance.
+#[async_trait]
+pub trait Fanout: Debug + Send + Sync {
+    async fn publish(&self, state: &AppState, chat_room_id: u32, message: BroadcastMessage) -> Result<(), ChatError>;
+
+    /// Gets the event to every socket the user is logged in with, the same way messages get to rooms.
+    async fn publish_session_event(&self, state: &AppState, user_id: u32, event: SessionEvent) -> Result<(), ChatError>;
+
+    /// Starts delivering what other instances publish to this instance's sockets. Called once, when the server starts.
+    fn start(&self, _state: Arc<AppState>) {}
+}
+
+/// Builds the fanout for the configured mode:
+/// - `local`: messages only reach sockets connected to this instance.
+/// - `redis`: messages go through Redis pub/sub, so they reach sockets connected to any instance.
+pub async fn fanout_from_config(config: &FanoutConfig) -> Result<Arc<dyn Fanout>, String> {
+    match config.mode {
+        FanoutMode::Local => Ok(Arc::new(LocalFanout)),
+        FanoutMode::Redis => {
+            let redis_url = config.redis_url.as_deref().ok_or("fanout.redis_url is required")?;
+            let fanout = RedisFanout::connect(redis_url, config.channel_prefix.clone(), config.session_channel_prefix.clone())
+                .await
+                .map_err(|error| format!("Couldn't connect to Redis: {error}"))?;
+            Ok(Arc::new(fanout))
+        }
+    }
+}
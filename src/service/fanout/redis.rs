@@ -0,0 +1,128 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chat_types::domain::chat_message::BroadcastMessage;
+use futures::StreamExt;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisError};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+use crate::domain::{error::ChatError, session::SessionEvent, state::AppState};
+
+use super::Fanout;
+
+/// How long to wait before subscribing again after losing the connection to Redis.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Publishes every message on the room's Redis channel and every session event on the user's, and every instance
+/// (this one included) forwards what comes in on those channels to its own sockets. Redis pub/sub doesn't keep
+/// messages around, so whatever gets published while an instance is reconnecting never reaches its sockets.
+pub struct RedisFanout {
+    client: redis::Client,
+    publisher: ConnectionManager,
+    channel_prefix: String,
+    session_channel_prefix: String,
+}
+
+impl Debug for RedisFanout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisFanout")
+            .field("channel_prefix", &self.channel_prefix)
+            .field("session_channel_prefix", &self.session_channel_prefix)
+            .finish()
+    }
+}
+
+impl RedisFanout {
+    pub async fn connect(redis_url: &str, channel_prefix: String, session_channel_prefix: String) -> Result<Self, RedisError> {
+        let client = redis::Client::open(redis_url)?;
+        let publisher = client.get_connection_manager().await?;
+        Ok(Self {
+            client,
+            publisher,
+            channel_prefix,
+            session_channel_prefix,
+        })
+    }
+
+    async fn publish_json(&self, channel: String, payload: &impl Serialize) -> Result<(), ChatError> {
+        let payload = serde_json::to_string(payload)
+            .map_err(|error| ChatError::Internal(format!("Couldn't serialize payload for Redis: {error}")))?;
+        let mut publisher = self.publisher.clone();
+        publisher
+            .publish::<_, _, ()>(channel, payload)
+            .await
+            .map_err(|error| ChatError::Internal(format!("Couldn't publish to Redis: {error}")))
+    }
+}
+
+#[async_trait]
+impl Fanout for RedisFanout {
+    async fn publish(&self, _state: &AppState, chat_room_id: u32, message: BroadcastMessage) -> Result<(), ChatError> {
+        self.publish_json(format!("{}{chat_room_id}", self.channel_prefix), &message).await
+    }
+
+    async fn publish_session_event(&self, _state: &AppState, user_id: u32, event: SessionEvent) -> Result<(), ChatError> {
+        self.publish_json(format!("{}{user_id}", self.session_channel_prefix), &event).await
+    }
+
+    fn start(&self, state: Arc<AppState>) {
+        let client = self.client.clone();
+        let channel_prefix = self.channel_prefix.clone();
+        let session_channel_prefix = self.session_channel_prefix.clone();
+        tokio::spawn(async move {
+            loop {
+                match forward_published(&client, &channel_prefix, &session_channel_prefix, &state).await {
+                    Ok(()) => warn!("Lost the Redis subscription, subscribing again"),
+                    Err(error) => warn!(%error, "Redis subscription failed, subscribing again"),
+                }
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+            }
+        });
+    }
+}
+
+/// Subscribes to every room's and every user's channel and hands what comes in to the rooms that are active on
+/// this instance and the sessions logged in on it. Only returns once the connection is gone.
+async fn forward_published(
+    client: &redis::Client,
+    channel_prefix: &str,
+    session_channel_prefix: &str,
+    state: &AppState,
+) -> Result<(), RedisError> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.psubscribe(format!("{channel_prefix}*")).await?;
+    pubsub.psubscribe(format!("{session_channel_prefix}*")).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let channel_name = message.get_channel_name();
+        if let Some(user_id) = channel_name.strip_prefix(session_channel_prefix).and_then(|user_id| user_id.parse::<u32>().ok()) {
+            match parse_payload::<SessionEvent>(&message) {
+                Ok(event) => {
+                    state.send_session_event(&user_id, event);
+                }
+                Err(error) => warn!(%error, user_id, "Dropping a session event that couldn't be parsed"),
+            }
+            continue;
+        }
+        let chat_room_id: u32 = match channel_name.strip_prefix(channel_prefix).and_then(|chat_room_id| chat_room_id.parse().ok()) {
+            Some(chat_room_id) => chat_room_id,
+            None => continue,
+        };
+        let broadcast_message = match parse_payload::<BroadcastMessage>(&message) {
+            Ok(broadcast_message) => broadcast_message,
+            Err(error) => {
+                warn!(%error, chat_room_id, "Dropping a message for chat room that couldn't be parsed");
+                continue;
+            }
+        };
+        // Rooms with nobody connected to this instance aren't in memory, there's no one to deliver to.
+        let _ = state.broadcast_to_local_subscribers(&chat_room_id, broadcast_message);
+    }
+    Ok(())
+}
+
+fn parse_payload<T: DeserializeOwned>(message: &redis::Msg) -> Result<T, String> {
+    let payload = message.get_payload::<String>().map_err(|error| format!("Invalid payload: {error}"))?;
+    serde_json::from_str(&payload).map_err(|error| error.to_string())
+}
@@ -1,154 +1,354 @@
-use actix_web::{HttpRequest};
-use actix_web_utils::extensions::typed_response::TypedHttpResponse;
 use chat_types::{domain::{chat_room::{ChatRoom}, chat_user::ChatUser}, dto::chat::ChatRoomParticipants};
-use dev_dtos::domain::user::user::User;
-use err::MessageResource;
-use reqwest::Client;
-use sqlx::MySqlPool;
 
-use crate::dao::chat_room_dao;
+use chrono::Utc;
 
-pub async fn get_all_user_chat_rooms(
-    conn: &MySqlPool,
-    _client: &Client,
-    user: User,
-    _request: HttpRequest,
-) -> TypedHttpResponse<Vec<ChatRoom>> {
-    let all_user_chat_rooms =
-        match chat_room_dao::fetch_all_user_chat_rooms(conn, user.id.try_into().unwrap()).await {
-            Ok(chat_rooms) => chat_rooms,
-            Err(error) => {
-                return TypedHttpResponse::return_standard_error(
-                    400,
-                    MessageResource::new_from_string(error.to_string()),
-                )
-            }
-        };
+use crate::{
+    config::OwnerLeavePolicy,
+    dao::{chat_room_dao, chat_room_kick_dao},
+    domain::{
+        chat_room_kick::{ChatRoomKick, KickRequest},
+        error::ChatError,
+        session::SessionEvent,
+        state::AppState,
+    },
+    service::{auth::AuthenticatedUser, user::validate_users_exist},
+};
 
-    TypedHttpResponse::return_standard_response(200, all_user_chat_rooms)
+/// Same as the reason column.
+const MAX_KICK_REASON_LENGTH: usize = 255;
+
+pub async fn get_all_user_chat_rooms(
+    state: &AppState,
+    user: AuthenticatedUser,
+) -> Result<Vec<ChatRoom>, ChatError> {
+    Ok(chat_room_dao::fetch_all_user_chat_rooms(&state.db_conn, user.id).await?)
 }
 
+/// The owner is always a participant, even if they didn't include themselves. The room and its participants
+/// get inserted in the same transaction, so if anything fails there's no room left behind without participants.
 pub async fn create_new_chat_room(
-    conn: &MySqlPool,
-    _client: &Client,
-    user: User,
-    _request: HttpRequest,
+    state: &AppState,
+    user: AuthenticatedUser,
     participants: ChatRoomParticipants,
     title: String
-) -> TypedHttpResponse<ChatRoom> {
-    // Create chat room
-    // Add all participants
-    let mut chat_room = ChatRoom::new(title, user.id.try_into().unwrap());
-    match chat_room_dao::insert_chat_room(conn, &chat_room).await {
-        Ok(persisted_id) => chat_room.id = persisted_id.try_into().unwrap(),
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+) -> Result<ChatRoom, ChatError> {
+    let mut participant_ids = vec![user.id];
+    for participant_id in participants.participants {
+        if !participant_ids.contains(&participant_id) {
+            participant_ids.push(participant_id);
+        }
+    }
+    if participant_ids.len() > state.max_room_size {
+        return Err(ChatError::Validation(format!("Chat rooms can't have more than {} participants.", state.max_room_size)));
+    }
+    validate_users_exist(state, &participant_ids[1..]).await?;
+
+    let mut chat_room = ChatRoom::new(title, user.id);
+    let mut transaction = state.db_conn.begin().await?;
+    let persisted_id = chat_room_dao::insert_chat_room(&mut transaction, &chat_room).await?;
+    chat_room.id = persisted_id.try_into().map_err(|_| ChatError::Internal(format!("Invalid chat room id: {persisted_id}")))?;
+    chat_room_dao::insert_chat_room_participants(&mut transaction, &participant_ids, &chat_room.id).await?;
+    transaction.commit().await?;
+    Ok(chat_room)
+}
+
+/// Every route that acts on a specific room goes through here first. Users that aren't in the room get a 403
+/// whether the room exists or not, so room ids can't be probed. Admins are let into any room that exists.
+/// Returns the room's participants.
+async fn require_membership(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    chat_room_id: u32,
+) -> Result<Vec<ChatUser>, ChatError> {
+    let participants = chat_room_dao::get_chat_room_participants(&state.db_conn, &chat_room_id).await?;
+    if participants.iter().any(|participant| participant.user_id == user.id) {
+        return Ok(participants);
+    }
+    if !state.is_admin(&user.id) {
+        return Err(ChatError::NotAMember("User doesn't belong to this chat room.".into()));
+    }
+    match chat_room_dao::get_chat_room_with_id(&state.db_conn, &chat_room_id).await? {
+        Some(_) => Ok(participants),
+        None => Err(ChatError::NotFound("Chat room with id specified doesn't exist.".into())),
+    }
+}
+
+/// Same as `require_membership`, but only the owner (or an admin) gets through. Returns the room.
+async fn require_ownership(
+    state: &AppState,
+    user: &AuthenticatedUser,
+    chat_room_id: u32,
+    action: &str,
+) -> Result<(ChatRoom, Vec<ChatUser>), ChatError> {
+    let participants = require_membership(state, user, chat_room_id).await?;
+    let chat_room = match chat_room_dao::get_chat_room_with_id(&state.db_conn, &chat_room_id).await? {
+        Some(chat_room) => chat_room,
+        None => return Err(ChatError::NotFound("Chat room with id specified doesn't exist.".into())),
     };
-    match chat_room_dao::insert_chat_room_participants(conn, &participants.participants, &chat_room.id).await {
-        Ok(_) => {},
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+    if chat_room.owner_id != user.id && !state.is_admin(&user.id) {
+        return Err(ChatError::Forbidden(format!("Only the owner of the chat room can {action}.")));
     }
-    TypedHttpResponse::return_standard_response(200, chat_room)
+    Ok((chat_room, participants))
 }
 
+/// The user lookups happen before anything is locked. The participants are then read again with the room locked,
+/// so concurrent adds can't push the room past `max_room_size` between the check and the insert.
 pub async fn add_participants_to_chat_room(
-    conn: &MySqlPool,
-    _client: &Client,
-    user: User,
-    _request: HttpRequest,
-    participants: ChatRoomParticipants,
+    state: &AppState,
+    user: AuthenticatedUser,
+    mut participants: ChatRoomParticipants,
     chat_room_id: u32,
-) -> TypedHttpResponse<ChatRoomParticipants> {
-    let persisted_chat_room = match chat_room_dao::get_chat_room_with_id(conn, &chat_room_id).await {
-        Ok(persisted_chat_room_opt) => match persisted_chat_room_opt {
-            Some(persisted_chat_room) => persisted_chat_room,
-            None => return TypedHttpResponse::return_empty_response(404),
-        },
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string())),
-    };
-    if persisted_chat_room.owner_id != user.id as u32 {
-        return TypedHttpResponse::return_standard_error(401, MessageResource::new_from_str("User requesting to add participants to chat room isn't the owner of the chat room..."));
+) -> Result<ChatRoomParticipants, ChatError> {
+    if participants.participants.is_empty() {
+        return Err(ChatError::Validation("The list of participants to add is empty.".into()));
     }
-    let persisted_chat_room_participants = match chat_room_dao::get_chat_room_participants(conn, &chat_room_id).await {
-        Ok(persisted_chat_room_participants) => persisted_chat_room_participants,
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string())),
-    };
-    if persisted_chat_room_participants.iter().any(|participant| participants.participants.contains(&participant.user_id)) {
-        return TypedHttpResponse::return_standard_error(400, MessageResource::new_from_str("At least one of the participants in the list to add is already in this chat room."));
+    participants.participants.sort_unstable();
+    participants.participants.dedup();
+    let (_, persisted_chat_room_participants) = require_ownership(state, &user, chat_room_id, "add participants").await?;
+    check_participants_to_add(state, &persisted_chat_room_participants, &participants.participants)?;
+    let banned_users = chat_room_kick_dao::get_banned_users(&state.db_conn, &chat_room_id, Utc::now()).await?;
+    if let Some(banned_user) = participants.participants.iter().find(|participant| banned_users.contains(participant)) {
+        return Err(ChatError::Forbidden(format!("User {banned_user} is banned from this chat room.")));
+    }
+    validate_users_exist(state, &participants.participants).await?;
+
+    let mut transaction = state.db_conn.begin().await?;
+    if chat_room_dao::lock_chat_room(&mut transaction, &chat_room_id).await?.is_none() {
+        return Err(ChatError::NotFound("Chat room with id specified doesn't exist.".into()));
+    }
+    let persisted_chat_room_participants = chat_room_dao::get_chat_room_participants(&mut transaction, &chat_room_id).await?;
+    check_participants_to_add(state, &persisted_chat_room_participants, &participants.participants)?;
+    chat_room_dao::insert_chat_room_participants(&mut transaction, &participants.participants, &chat_room_id).await?;
+    transaction.commit().await?;
+    Ok(participants)
+}
+
+fn check_participants_to_add(state: &AppState, persisted: &[ChatUser], to_add: &[u32]) -> Result<(), ChatError> {
+    if persisted.iter().any(|participant| to_add.contains(&participant.user_id)) {
+        return Err(ChatError::Conflict("At least one of the participants in the list to add is already in this chat room.".into()));
     };
-    match chat_room_dao::insert_chat_room_participants(conn, &participants.participants, &chat_room_id).await {
-        Ok(_) => {},
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+    if persisted.len() + to_add.len() > state.max_room_size {
+        return Err(ChatError::Validation(format!("Chat rooms can't have more than {} participants.", state.max_room_size)));
     }
-    TypedHttpResponse::return_standard_response(200, participants)
+    Ok(())
 }
 
 pub async fn get_chat_room_participants(
-    conn: &MySqlPool,
-    _client: &Client,
-    _user: User,
-    _request: HttpRequest,
+    state: &AppState,
+    user: AuthenticatedUser,
     chat_room_id: u32,
-) -> TypedHttpResponse<Vec<ChatUser>> {
-    match chat_room_dao::get_chat_room_participants(conn, &chat_room_id).await {
-        Ok(participants) => TypedHttpResponse::return_standard_response(200, participants),
-        Err(error) => TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
-    }
+) -> Result<Vec<ChatUser>, ChatError> {
+    require_membership(state, &user, chat_room_id).await
 }
 
+/// When the owner leaves a room that still has other participants, `owner_leave_policy` decides whether they're
+/// stopped or the participant that's been in the room the longest takes over. With `delete_empty_rooms` on, the
+/// last participant to leave takes the room with them.
 pub async fn leave_chat_room(
-    conn: &MySqlPool,
-    _client: &Client,
-    user: User,
-    _request: HttpRequest,
+    state: &AppState,
+    user: AuthenticatedUser,
     chat_room_id: u32,
-) -> TypedHttpResponse<ChatUser> {
-    let participants = match chat_room_dao::get_chat_room_participants(conn, &chat_room_id).await {
-        Ok(participants) => participants,
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
-    };
-    if participants.len() <= 0 || participants.iter().find(|participant| participant.user_id == user.id as u32).is_none() {
-        return TypedHttpResponse::return_standard_error(404, MessageResource::new_from_str("User doesn't belong to this chat room."));
+) -> Result<(), ChatError> {
+    let mut transaction = state.db_conn.begin().await?;
+    let owner_id = chat_room_dao::lock_chat_room(&mut transaction, &chat_room_id).await?;
+    let participants = chat_room_dao::get_chat_room_participants(&mut transaction, &chat_room_id).await?;
+    // Admins get no special treatment here, there's nothing to leave if they're not in the room.
+    let owner_id = match owner_id {
+        Some(owner_id) if participants.iter().any(|participant| participant.user_id == user.id) => owner_id,
+        _ => return Err(ChatError::NotAMember("User doesn't belong to this chat room.".into())),
     };
-    match chat_room_dao::delete_chat_room_participant(conn, &chat_room_id, user.id as u32).await {
-        Ok(deleted_opt) => match deleted_opt {
-            Some(_) => TypedHttpResponse::return_empty_response(200),
-            None => TypedHttpResponse::return_standard_error(404, MessageResource::new_from_str("Couldn't delete participant from chat room")),
-        },
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+    let longest_standing = participants
+        .iter()
+        .filter(|participant| participant.user_id != user.id)
+        .min_by_key(|participant| (participant.time_joined, participant.user_id));
+
+    match longest_standing {
+        None if state.delete_empty_rooms => {
+            chat_room_dao::delete_chat_room(&mut transaction, &chat_room_id).await?;
+        }
+        Some(_) if owner_id == user.id && state.owner_leave_policy == OwnerLeavePolicy::Block => {
+            return Err(ChatError::Forbidden(
+                "The owner can't leave the chat room while there are other participants in it.".into(),
+            ));
+        }
+        longest_standing => {
+            if chat_room_dao::delete_chat_room_participant(&mut transaction, &chat_room_id, user.id).await?.is_none() {
+                return Err(ChatError::NotFound("Couldn't delete participant from chat room".into()));
+            }
+            if let Some(new_owner) = longest_standing.filter(|_| owner_id == user.id) {
+                chat_room_dao::update_chat_room_owner(&mut transaction, &chat_room_id, new_owner.user_id).await?;
+            }
+        }
     }
+    transaction.commit().await?;
+    state.publish_session_event(user.id, SessionEvent::LeftRoom(chat_room_id)).await;
+    Ok(())
 }
 
+/// Takes the user out of the room and records who kicked them, when and why. If the kick comes with `bannedUntil`
+/// the user can't be added back before then. If the user is connected they get a KICKED frame and stop getting
+/// the room's messages right away.
 pub async fn kick_user_from_chat_room(
-    conn: &MySqlPool,
-    _client: &Client,
-    user: User,
-    _request: HttpRequest,
+    state: &AppState,
+    user: AuthenticatedUser,
     chat_room_id: u32,
     user_to_be_kicked: u32,
-) -> TypedHttpResponse<ChatUser> {
-    let participants = match chat_room_dao::get_chat_room_participants(conn, &chat_room_id).await {
-        Ok(participants) => participants,
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
-    };
-    if participants.len() <= 0 || participants.iter().find(|participant| participant.user_id == user_to_be_kicked).is_none() {
-        return TypedHttpResponse::return_standard_error(404, MessageResource::new_from_str("User doesn't belong to this chat room."));
+    kick_request: KickRequest,
+) -> Result<ChatRoomKick, ChatError> {
+    let (chat_room, participants) = require_ownership(state, &user, chat_room_id, "kick participants").await?;
+    if user_to_be_kicked == chat_room.owner_id {
+        return Err(ChatError::Forbidden("The owner of the chat room can't be kicked.".into()));
+    }
+    if !participants.iter().any(|participant| participant.user_id == user_to_be_kicked) {
+        return Err(ChatError::NotFound("User doesn't belong to this chat room.".into()));
     };
-    let chat_room = match chat_room_dao::get_chat_room_with_id(conn, &chat_room_id).await {
-        Ok(chat_room_opt) => match chat_room_opt {
-            Some(chat_room) => chat_room,
-            None => return TypedHttpResponse::return_standard_error(404, MessageResource::new_from_str("Chat room with id specified doesn't exist. ")),
-        },
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+    if let Some(banned_until) = kick_request.banned_until {
+        if banned_until <= Utc::now() {
+            return Err(ChatError::Validation("bannedUntil must be in the future.".into()));
+        }
+    }
+    if kick_request.reason.as_ref().is_some_and(|reason| reason.chars().count() > MAX_KICK_REASON_LENGTH) {
+        return Err(ChatError::Validation(format!("Kick reasons can't be longer than {MAX_KICK_REASON_LENGTH} characters.")));
+    }
+
+    let mut kick = ChatRoomKick::new(chat_room_id, user_to_be_kicked, user.id, kick_request);
+    let mut transaction = state.db_conn.begin().await?;
+    if chat_room_dao::delete_chat_room_participant(&mut transaction, &chat_room_id, user_to_be_kicked).await?.is_none() {
+        return Err(ChatError::NotFound("Couldn't delete participant from chat room".into()));
+    }
+    let persisted_id = chat_room_kick_dao::insert_chat_room_kick(&mut transaction, &kick).await?;
+    kick.id = persisted_id.try_into().map_err(|_| ChatError::Internal(format!("Invalid kick id: {persisted_id}")))?;
+    transaction.commit().await?;
+
+    state.publish_session_event(user_to_be_kicked, SessionEvent::Kicked(kick.clone())).await;
+    Ok(kick)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chat_types::dto::chat::ChatRoomParticipants;
+    use chrono::{Duration, Utc};
+    use sqlx::MySqlPool;
+
+    use crate::{
+        config::{Config, OwnerLeavePolicy},
+        dao::chat_room_dao,
+        domain::{error::ChatError, state::AppState},
+        service::auth::AuthenticatedUser,
+        util::test_util::test_state,
     };
-    if chat_room.owner_id != user.id as u32 {
-        return TypedHttpResponse::return_standard_error(401, MessageResource::new_from_str("You are not the owner of this chat room."));
+
+    const OWNER_ID: u32 = 1;
+    /// Has a higher id than NEWER_MEMBER_ID, so the transfer can't come down to picking the lowest id.
+    const MEMBER_ID: u32 = 5;
+    const NEWER_MEMBER_ID: u32 = 2;
+
+    fn state_with(pool: MySqlPool, owner_leave_policy: OwnerLeavePolicy, delete_empty_rooms: bool) -> Arc<AppState> {
+        test_state(pool, |config: &mut Config| {
+            config.rooms.owner_leave_policy = owner_leave_policy;
+            config.rooms.delete_empty_rooms = delete_empty_rooms;
+        })
+    }
+
+    /// A room owned by OWNER_ID with MEMBER_ID in it and, if asked for, NEWER_MEMBER_ID joining after them.
+    async fn room(state: &AppState, with_newer_member: bool) -> u32 {
+        let participants = ChatRoomParticipants { participants: vec![MEMBER_ID] };
+        let chat_room = super::create_new_chat_room(state, user(OWNER_ID), participants, "leave-test".into()).await.unwrap();
+        if with_newer_member {
+            let participants = ChatRoomParticipants { participants: vec![NEWER_MEMBER_ID] };
+            super::add_participants_to_chat_room(state, user(OWNER_ID), participants, chat_room.id).await.unwrap();
+            // Both get added within the same second, and join times only have second precision.
+            let joined = Utc::now() - Duration::hours(1);
+            let join_times = [(OWNER_ID, joined), (MEMBER_ID, joined), (NEWER_MEMBER_ID, joined + Duration::minutes(1))];
+            for (user_id, time_joined) in join_times {
+                sqlx::query("UPDATE chat_users SET time_joined = ? WHERE chat_room_id = ? AND user_id = ?")
+                    .bind(time_joined)
+                    .bind(chat_room.id)
+                    .bind(user_id)
+                    .execute(&state.db_conn)
+                    .await
+                    .unwrap();
+            }
+        }
+        chat_room.id
     }
-    match chat_room_dao::delete_chat_room_participant(conn, &chat_room_id, user.id as u32).await {
-        Ok(deleted_opt) => match deleted_opt {
-            Some(_) => TypedHttpResponse::return_empty_response(200),
-            None => TypedHttpResponse::return_standard_error(404, MessageResource::new_from_str("Couldn't delete participant from chat room")),
-        },
-        Err(error) => return TypedHttpResponse::return_standard_error(500, MessageResource::new_from_string(error.to_string()))
+
+    fn user(id: u32) -> AuthenticatedUser {
+        AuthenticatedUser { id }
     }
 
-}
\ No newline at end of file
+    async fn owner_and_participants(state: &AppState, chat_room_id: u32) -> Option<(u32, Vec<u32>)> {
+        let chat_room = chat_room_dao::get_chat_room_with_id(&state.db_conn, &chat_room_id).await.unwrap()?;
+        let mut participants: Vec<u32> = chat_room_dao::get_chat_room_participants(&state.db_conn, &chat_room_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|participant| participant.user_id)
+            .collect();
+        participants.sort_unstable();
+        Some((chat_room.owner_id, participants))
+    }
+
+    #[sqlx::test]
+    async fn block_stops_the_owner_from_leaving(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Block, true);
+        let chat_room_id = room(&state, false).await;
+        let result = super::leave_chat_room(&state, user(OWNER_ID), chat_room_id).await;
+        assert!(matches!(result, Err(ChatError::Forbidden(_))), "{result:?}");
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((OWNER_ID, vec![OWNER_ID, MEMBER_ID])));
+    }
+
+    #[sqlx::test]
+    async fn block_lets_the_owner_leave_once_they_are_alone(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Block, false);
+        let chat_room_id = room(&state, false).await;
+        super::leave_chat_room(&state, user(MEMBER_ID), chat_room_id).await.unwrap();
+        super::leave_chat_room(&state, user(OWNER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((OWNER_ID, vec![])));
+    }
+
+    #[sqlx::test]
+    async fn transfer_hands_the_room_to_the_longest_standing_member(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Transfer, true);
+        let chat_room_id = room(&state, true).await;
+        super::leave_chat_room(&state, user(OWNER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((MEMBER_ID, vec![NEWER_MEMBER_ID, MEMBER_ID])));
+    }
+
+    #[sqlx::test]
+    async fn members_leaving_dont_change_the_owner(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Transfer, true);
+        let chat_room_id = room(&state, true).await;
+        super::leave_chat_room(&state, user(MEMBER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((OWNER_ID, vec![OWNER_ID, NEWER_MEMBER_ID])));
+    }
+
+    #[sqlx::test]
+    async fn non_members_cant_leave(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Transfer, true);
+        let chat_room_id = room(&state, false).await;
+        let result = super::leave_chat_room(&state, user(NEWER_MEMBER_ID), chat_room_id).await;
+        assert!(matches!(result, Err(ChatError::NotAMember(_))), "{result:?}");
+    }
+
+    #[sqlx::test]
+    async fn the_last_member_leaving_deletes_the_room_when_empty_rooms_get_deleted(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Transfer, true);
+        let chat_room_id = room(&state, false).await;
+        super::leave_chat_room(&state, user(OWNER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((MEMBER_ID, vec![MEMBER_ID])));
+        super::leave_chat_room(&state, user(MEMBER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, None);
+    }
+
+    #[sqlx::test]
+    async fn the_last_member_leaving_keeps_the_room_when_empty_rooms_are_kept(pool: MySqlPool) {
+        let state = state_with(pool, OwnerLeavePolicy::Transfer, false);
+        let chat_room_id = room(&state, false).await;
+        super::leave_chat_room(&state, user(OWNER_ID), chat_room_id).await.unwrap();
+        super::leave_chat_room(&state, user(MEMBER_ID), chat_room_id).await.unwrap();
+        assert_eq!(owner_and_participants(&state, chat_room_id).await, Some((MEMBER_ID, vec![])));
+    }
+}
@@ -2,33 +2,42 @@ use std::{sync::Arc, time::Duration};
 
 use chat_types::domain::{
     chat_message::{BroadcastMessage, ChatMessage, ChatSendable, TimeSensitiveAction},
-    chat_message_update::ChatMessageUpdate, error::SocketError,
+    chat_message_update::ChatMessageUpdate,
 };
 use chrono::Utc;
 use tokio::time::sleep;
+use metrics::increment_counter;
+use tracing::{debug, error, warn, Instrument};
 
 use crate::{
     dao::message_dao::{self, insert_message},
-    domain::state::AppState,
+    domain::{error::ChatError, state::AppState},
 };
 
+/// Messages persisted and published to their room.
+pub const MESSAGES_SENT_METRIC: &str = "chat_messages_sent_total";
+/// Delivered updates persisted, one per message per recipient.
+pub const MESSAGES_DELIVERED_METRIC: &str = "chat_messages_delivered_total";
+/// Seen updates persisted, one per message per reader.
+pub const MESSAGES_SEEN_METRIC: &str = "chat_messages_seen_total";
+
 /// Gets called when a message is recieved from a socket client, this broadcasts it to all the connected sockets
-/// And persists it.
+/// And persists it. Once the message is persisted this succeeds, even if publishing it didn't.
 pub async fn user_send_message(
     state: Arc<AppState>,
     user_id: u32,
     message: BroadcastMessage,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(), ChatError> {
     let chat_rooms_user_belongs_to = match state.get_all_user_chat_rooms(&user_id) {
         Some(chat_rooms) => chat_rooms,
-        None => return Err(SocketError::boxed_error("User doesn't have any rooms.")),
+        None => return Err(ChatError::NotAMember("User doesn't have any rooms.".into())),
     };
 
     // In reality, the NewMessage will never go in this method as it is what comes out
     let to = match &message {
         BroadcastMessage::NewMessageRequest(new_message_req) => new_message_req.to,
         BroadcastMessage::NewMessage(new_message) => {
-            println!("BroadCastMessage::NewMessage Variant passed in to user_send_message method. This shouldn't be happening. SoftError");
+            warn!("BroadCastMessage::NewMessage Variant passed in to user_send_message method. This shouldn't be happening. SoftError");
             new_message.to_id
         }
         BroadcastMessage::DeliveredUpdate(delivered_update) => delivered_update.to_id,
@@ -36,12 +45,11 @@ pub async fn user_send_message(
     };
 
     if !chat_rooms_user_belongs_to.contains(&to) {
-        return Err(SocketError::boxed_error(
-            "User just tried to send a message to a room he doesn't belong to.",
+        return Err(ChatError::NotAMember(
+            "User just tried to send a message to a room he doesn't belong to.".into(),
         ));
     }
 
-    let broadcast_sender = state.get_cloned_broadcast_sender_to_chat_room(&to)?;
     match message.clone() {
         BroadcastMessage::NewMessageRequest(new_message_req) => {
             let mut chat_message_to_send = ChatMessage::new(user_id, new_message_req);
@@ -49,13 +57,51 @@ pub async fn user_send_message(
                 .await?
                 .try_into()
                 .unwrap();
-            let _ = broadcast_sender.send(BroadcastMessage::NewMessage(chat_message_to_send))?;
+            increment_counter!(MESSAGES_SENT_METRIC);
+            // The message is already persisted, failing here would make the client send it again and store it
+            // twice. Whoever missed it gets it from the room's history.
+            let message_id = chat_message_to_send.id;
+            if let Err(error) = state.fanout.publish(&state, to, BroadcastMessage::NewMessage(chat_message_to_send)).await {
+                warn!(%error, chat_room_id = to, message_id, "Message was saved but couldn't be published to the room");
+            }
             return Ok(());
         }
         _ => {}
     };
-    let _ = broadcast_sender.send(message)?;
-    Ok(())
+    state.fanout.publish(&state, to, message).await
+}
+
+/// Persists that the message got delivered to the user and sends the update to the room. Updates to the same
+/// message get applied one at a time, in the order they were queued.
+pub async fn mark_message_delivered(state: Arc<AppState>, user_id: u32, message_id: u32) {
+    let message_update = ChatMessageUpdate::Delivered(user_id, Utc::now());
+    state.add_message_update_to_queue(&message_id, message_update.clone());
+    while !state.is_update_first_in_queue(&message_id, &message_update) {
+        // Wait 50ms
+        sleep(Duration::from_millis(50)).await;
+    }
+    match message_dao::get_message(&state.db_conn, &message_id).await {
+        Ok(Some(mut persisted_message)) => {
+            persisted_message.time_delivered.list.push(TimeSensitiveAction::new(user_id));
+            match message_dao::update_message(&state.db_conn, &persisted_message).await {
+                // Broadcast the delivered message to all connected sockets,
+                // Since they already have that MessageId stored, they can handle it as an update
+                Ok(_) => {
+                    increment_counter!(MESSAGES_DELIVERED_METRIC);
+                    match user_send_message(state.clone(), user_id, BroadcastMessage::DeliveredUpdate(persisted_message)).await {
+                        Ok(_) => {}
+                        Err(error) => error!(%error, "Error sending a chat message update to a client"),
+                    }
+                }
+                Err(error) => error!(%error, "Something went wrong in the database while performing an update to the message table"),
+            }
+        }
+        Ok(None) => {}
+        Err(error) => error!(%error, "Something went wrong in the database while performing a get to the message table"),
+    }
+    if state.remove_first_message_update_from_queue(&message_id).is_none() {
+        error!(message_id, "Error while removing the first message from the message update queue. This should never happen.");
+    }
 }
 
 /// Method called when the client sends to the server that they saw the message(s) sent to them
@@ -64,10 +110,10 @@ pub async fn see_messages(
     state: &Arc<AppState>,
     user_id: &u32,
     message_ids: Vec<u32>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(), ChatError> {
     if message_ids.len() == 0 {
-        return Err(SocketError::boxed_error(
-            "Empty list of message_ids to see... What are you trying to do?",
+        return Err(ChatError::Validation(
+            "Empty list of message_ids to see... What are you trying to do?".into(),
         ));
     };
     // Find messages in DB
@@ -75,8 +121,8 @@ pub async fn see_messages(
         message_dao::fetch_messages_with_ids(&state.db_conn, &message_ids).await?;
     // Make sure they all correspond to the same chat_room
     if persisted_messages.len() == 0 || persisted_messages.len() != message_ids.len() {
-        return Err(SocketError::boxed_error(
-            "MessageIds don't exist in the databse.",
+        return Err(ChatError::NotFound(
+            "MessageIds don't exist in the databse.".into(),
         ));
     };
     // Grab the room id of the first message
@@ -86,18 +132,18 @@ pub async fn see_messages(
         .iter()
         .all(|persisted_message| persisted_message.to_id == room_id)
     {
-        return Err(SocketError::boxed_error(
-            "All Messages don't have the same roomId",
+        return Err(ChatError::Validation(
+            "All Messages don't have the same roomId".into(),
         ));
     };
     // Check that the user belongs to this chat room
     let chat_rooms_user_belongs_to = match state.get_all_user_chat_rooms(&user_id) {
         Some(chat_rooms) => chat_rooms,
-        None => return Err(SocketError::boxed_error("User doesn't have any rooms.")),
+        None => return Err(ChatError::NotAMember("User doesn't have any rooms.".into())),
     };
     if !chat_rooms_user_belongs_to.contains(&room_id) {
-        return Err(SocketError::boxed_error(
-            "User just tried to see a message in a room he doesn't belong to.",
+        return Err(ChatError::NotAMember(
+            "User just tried to see a message in a room he doesn't belong to.".into(),
         ));
     };
 
@@ -106,7 +152,7 @@ pub async fn see_messages(
     let cloned_state = state.clone();
     let cloned_user_id = user_id.clone();
     let cloned_message_ids = message_ids.clone();
-    tokio::task::spawn(async move {
+    state.tasks.spawn(async move {
         let time_seen = Utc::now();
         for message_id in cloned_message_ids {
             if !cloned_state.does_message_have_updates_in_queue(&message_id) {
@@ -125,7 +171,7 @@ pub async fn see_messages(
                 {
                     Ok(persisted_message_opt) => persisted_message_opt,
                     Err(error) => {
-                        println!("Something went wrong in the database while performing a get to the message table. Error: {}", error);
+                        error!(%error, "Something went wrong in the database while performing a get to the message table");
                         None
                     }
                 };
@@ -137,7 +183,7 @@ pub async fn see_messages(
                         .iter()
                         .any(|time_seen| time_seen.by == cloned_user_id)
                     {
-                        println!("User is attempting to read a message that is already read by that user. Breaking the loop...");
+                        debug!(message_id, "User is attempting to read a message that is already read by that user. Breaking the loop...");
                         break;
                     }
                     persisted_message.time_seen.list.push(TimeSensitiveAction {
@@ -146,19 +192,20 @@ pub async fn see_messages(
                     });
                     match message_dao::update_message(&cloned_state.db_conn, &persisted_message).await {
                             Ok(_) => {
+                                increment_counter!(MESSAGES_SEEN_METRIC);
                                 // Broadcast the delivered message to all connected sockets, 
                                 // The idea is that the clients get the same chatmessage,
                                 // Since they already have that MessageId stored, they can handle it as an update
                                 match user_send_message(cloned_state.clone(), cloned_user_id, BroadcastMessage::SeenUpdate(persisted_message)).await {
                                     Ok(_) => {},
-                                    Err(error) => println!("Error sending a chat message update to a client: {error}"),
+                                    Err(error) => error!(%error, "Error sending a chat message update to a client"),
                                 };
                             },
-                            Err(error) => println!("Something went wrong in the database while performing an update to the message table. Error: {error}"),
+                            Err(error) => error!(%error, "Something went wrong in the database while performing an update to the message table"),
                         };
                     match cloned_state.remove_first_message_update_from_queue(&message_id) {
                             Some(_) => {},
-                            None => println!("Error while removing the first message from the message update queue. This should never happen. MessageId: {}", message_id),
+                            None => error!(message_id, "Error while removing the first message from the message update queue. This should never happen."),
                         };
                 }
             } else {
@@ -180,7 +227,7 @@ pub async fn see_messages(
                 {
                     Ok(persisted_message_opt) => persisted_message_opt,
                     Err(error) => {
-                        println!("Something went wrong in the database while performing a get to the message table. Error: {}", error);
+                        error!(%error, "Something went wrong in the database while performing a get to the message table");
                         None
                     }
                 };
@@ -192,7 +239,7 @@ pub async fn see_messages(
                         .iter()
                         .any(|time_seen| time_seen.by == cloned_user_id)
                     {
-                        println!("User is attempting to read a message that is already read by that user. Breaking the loop...");
+                        debug!(message_id, "User is attempting to read a message that is already read by that user. Breaking the loop...");
                         break;
                     }
                     persisted_message
@@ -201,25 +248,26 @@ pub async fn see_messages(
                         .push(TimeSensitiveAction::new(cloned_user_id));
                     match message_dao::update_message(&cloned_state.db_conn, &persisted_message).await {
                             Ok(_) => {
+                                increment_counter!(MESSAGES_SEEN_METRIC);
                                 // Broadcast the seen message to all connected sockets, 
                                 // The idea is that the clients get the same chatmessage,
                                 // Since they already have that MessageId stored, they can handle it as an update
                                 match user_send_message(cloned_state.clone(), cloned_user_id, BroadcastMessage::SeenUpdate(persisted_message)).await {
                                     Ok(_) => {},
-                                    Err(error) => println!("Error sending a chat message update to a client: {error}"),
+                                    Err(error) => error!(%error, "Error sending a chat message update to a client"),
                                 };
                             },
-                            Err(error) => println!("Something went wrong in the database while performing an update to the message table. Error: {error}"),
+                            Err(error) => error!(%error, "Something went wrong in the database while performing an update to the message table"),
                         };
                     match cloned_state.remove_first_message_update_from_queue(&message_id) {
                             Some(_) => {},
-                            None => println!("Error while removing the first message from the message update queue. This should never happen. MessageId: {}", message_id),
+                            None => error!(message_id, "Error while removing the first message from the message update queue. This should never happen."),
                         };
                 }
             }
         }
         // Pasted
-    });
+    }.in_current_span());
 
     Ok(())
 }
@@ -1,3 +1,6 @@
+pub mod auth;
+pub mod fanout;
 pub mod http;
 pub mod message;
+pub mod rate_limit;
 pub mod user;
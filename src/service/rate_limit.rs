@@ -0,0 +1,61 @@
+use chat_types::dto::server_in::ServerMessageIn;
+
+use crate::{config::RateLimitsConfig, domain::error::ChatError, util::rate_limit::RateLimiter};
+
+/// Every rate limit the server enforces, each one a token bucket per user or per room.
+#[derive(Debug)]
+pub struct RateLimits {
+    send_message: RateLimiter<u32>,
+    send_message_per_room: RateLimiter<u32>,
+    see_messages: RateLimiter<u32>,
+    http: RateLimiter<u32>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitsConfig) -> Self {
+        Self {
+            send_message: RateLimiter::new(config.send_message),
+            send_message_per_room: RateLimiter::new(config.send_message_per_room),
+            see_messages: RateLimiter::new(config.see_messages),
+            http: RateLimiter::new(config.http),
+        }
+    }
+
+    /// Checks a frame from a logged in socket. Frames without a limit of their own always get through. Messages
+    /// need a token from both the user's and the room's bucket, neither gets taken unless both have one. Only
+    /// check messages to rooms the user is in, so nobody else can use up a room's limit.
+    pub fn check_message(&self, user_id: u32, message: &ServerMessageIn) -> Result<(), ChatError> {
+        match message {
+            ServerMessageIn::SendMessage(new_message) => {
+                if !self.send_message.has_token(&user_id) {
+                    return Err(ChatError::RateLimited("You're sending messages too fast.".into()));
+                }
+                if !self.send_message_per_room.has_token(&new_message.to) {
+                    return Err(ChatError::RateLimited("This chat room is getting too many messages, try again in a bit.".into()));
+                }
+                self.send_message.check(user_id);
+                self.send_message_per_room.check(new_message.to);
+                Ok(())
+            }
+            ServerMessageIn::SeeMessages(_) if !self.see_messages.check(user_id) => {
+                Err(ChatError::RateLimited("You're marking messages as seen too fast.".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_http(&self, user_id: u32) -> Result<(), ChatError> {
+        match self.http.check(user_id) {
+            true => Ok(()),
+            false => Err(ChatError::RateLimited("Too many requests, slow down.".into())),
+        }
+    }
+
+    /// Forgets users and rooms that haven't done anything in a while, so the buckets don't pile up.
+    pub fn prune(&self) {
+        self.send_message.prune();
+        self.send_message_per_room.prune();
+        self.see_messages.prune();
+        self.http.prune();
+    }
+}
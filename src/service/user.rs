@@ -1,206 +1,136 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, sync::Arc};
 
-use axum::extract::ws::{Message, WebSocket};
-use chat_types::{domain::{
-    chat_message::{BroadcastMessage, TimeSensitiveAction},
-    chat_message_update::ChatMessageUpdate, error::{MUTEX_LOCK_ERROR_MESSAGE, SocketError},
-}, dto::{server_in::ServerMessageIn, server_out::ServerMessageOut}};
-use chrono::Utc;
-use dev_communicators::middleware::user_svc::user_service;
-use futures::stream::SplitSink;
-use tokio::{sync::Mutex, task::JoinHandle, time::sleep};
+use chat_types::dto::{server_in::ServerMessageIn, server_out::ServerMessageOut};
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::debug;
 
 use crate::{
-    dao::{chat_room_dao, message_dao},
-    domain::state::AppState,
-    net::{
-        utils::send_message,
-    },
-    service::message::user_send_message,
+    dao::chat_room_dao,
+    domain::{chat_room_channel::RoomSubscriptions, error::ChatError, state::AppState},
+    net::{outbound::Outbound, utils::send_reply},
+    service::auth::Credentials,
 };
 
+/// How many participant lookups a single request can have in flight.
+const MAX_CONCURRENT_USER_LOOKUPS: usize = 8;
+
+/// Looks up every user id at the participant lookup url (if one is configured) and fails on the first one that doesn't exist.
+pub async fn validate_users_exist(state: &AppState, user_ids: &[u32]) -> Result<(), ChatError> {
+    let participant_lookup_url = match &state.participant_lookup_url {
+        Some(participant_lookup_url) => participant_lookup_url,
+        None => return Ok(()),
+    };
+    let lookups = stream::iter(user_ids.iter().copied()).map(|user_id| async move {
+        let response = state
+            .conn
+            .get(participant_lookup_url.replace("{user_id}", &user_id.to_string()))
+            .send()
+            .await
+            .map_err(|error| ChatError::Internal(format!("Couldn't look up user {user_id}: {error}")))?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(ChatError::Validation(format!("User {user_id} doesn't exist."))),
+            status => Err(ChatError::Internal(format!("Looking up user {user_id} failed with status {status}"))),
+        }
+    });
+    lookups.buffer_unordered(MAX_CONCURRENT_USER_LOOKUPS).try_collect::<()>().await
+}
+
 pub fn is_addr_registered(state: &AppState, addr: &SocketAddr) -> Option<u32> {
-    let connected_clients = state
-        .connected_clients
-        .lock()
-        .expect(MUTEX_LOCK_ERROR_MESSAGE);
-    connected_clients.get(addr).copied()
+    state.connected_clients.get(addr).map(|user_id| *user_id)
 }
 
 /// This method performs all necessary network requests to register a socket address with a user id and find the rooms it belongs to.
 pub async fn register_addr(
     state: Arc<AppState>,
     addr: &SocketAddr,
-    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    sender: &Outbound,
     message: &ServerMessageIn,
-    all_send_tasks: &mut Vec<JoinHandle<()>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    request_id: &Option<Value>,
+    subscriptions: &mut RoomSubscriptions,
+) -> Result<(), ChatError> {
+    let credentials = login_credentials(message)?;
+    let user = state.authenticator.authenticate(&credentials).await?;
+    register_authenticated_addr(state, addr, sender, user.id, credentials, request_id, subscriptions).await
+}
+
+/// Takes the credentials out of a Login message.
+pub fn login_credentials(message: &ServerMessageIn) -> Result<Credentials, ChatError> {
     let user_for_auth = match message {
         ServerMessageIn::Login(user_for_auth) => user_for_auth,
         _ => {
-            return Err(SocketError::boxed_error(format!(
-                "Non authorized user attempting to perform authed action. Message: {:?}",
-                message
-            )))
+            return Err(ChatError::AuthFailed(
+                "Non authorized user attempting to perform authed action. Login first.".into(),
+            ))
         }
     };
+    // The login body is {"id": ..., "token": ...}, the id is optional when the token is a JWT.
+    let user_for_auth = serde_json::to_value(user_for_auth)?;
+    Ok(Credentials {
+        user_id: match user_for_auth.get("id") {
+            Some(Value::String(user_id)) if !user_id.is_empty() => Some(user_id.clone()),
+            Some(Value::Number(user_id)) => Some(user_id.to_string()),
+            _ => None,
+        },
+        token: user_for_auth
+            .get("token")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
 
-    // Auth user
-    let persisted_user =
-        user_service::authenticate_user_with_token(&state.conn, user_for_auth).await?;
-    let _ = send_message(sender.clone(), ServerMessageOut::LoggedIn).await;
-    let user_id = persisted_user.id.try_into()?;
-    // Store user id along with socket
-    state.add_connected_client(*addr, user_id)?;
+/// Ties an already authenticated user to the socket address and subscribes the socket to all the user's rooms.
+/// The credentials are kept with the session so they can be checked again later on. The client only gets told
+/// it's logged in once all of that worked, if anything fails whatever was already stored gets taken out again.
+pub async fn register_authenticated_addr(
+    state: Arc<AppState>,
+    addr: &SocketAddr,
+    sender: &Outbound,
+    user_id: u32,
+    credentials: Credentials,
+    request_id: &Option<Value>,
+    subscriptions: &mut RoomSubscriptions,
+) -> Result<(), ChatError> {
     // Find rooms user belongs to
     let all_user_chat_rooms =
         chat_room_dao::fetch_all_user_chat_rooms(&state.db_conn, user_id).await?;
-    println!("{:?}", all_user_chat_rooms);
+    debug!(chat_rooms = all_user_chat_rooms.len(), "Logged in, subscribing to chat rooms");
     let all_user_chat_room_ids: Vec<u32> = all_user_chat_rooms
         .into_iter()
         .map(|room| room.id)
         .collect();
 
+    // Fails if the user is already logged in on another socket, before anything about this one is stored.
     state.add_user_with_rooms(user_id, all_user_chat_room_ids.clone())?;
+    // Store user id along with socket
+    if let Err(error) = state.add_connected_client(*addr, user_id) {
+        unregister_addr(&state, addr, &user_id);
+        return Err(error);
+    }
+    state.add_session(*addr, user_id, credentials);
+    // All of the rooms get forwarded to the client by the socket's own loop, see forward_room_message.
     for chat_room_id in all_user_chat_room_ids {
-        let _ = state.add_chat_room_channel(chat_room_id, &user_id)?;
-        let mut channel_reciever_handle = state.subscribe_to_channel(&chat_room_id)?;
-
-        let sender_cloned_ref = sender.clone();
-        let cloned_state = state.clone();
-        let cloned_user_id = user_id.clone(); // The recipient's user id
-
-        // This here spawns a new task that will forward messages that get sent to the channel to the client connected to the current socket.
-        let sender_task = tokio::spawn(async move {
-            while let Ok(msg) = channel_reciever_handle.recv().await {
-                let message_to_send_to_client = match msg.clone() {
-                    BroadcastMessage::NewMessage(message) => {
-                        ServerMessageOut::MessageRecieved(message)
-                    }
-                    BroadcastMessage::DeliveredUpdate(delivered_update) => {
-                        ServerMessageOut::MessageDelivered(delivered_update)
-                    }
-                    BroadcastMessage::SeenUpdate(seen_update) => {
-                        ServerMessageOut::MessageSeen(seen_update)
-                    }
-                    BroadcastMessage::NewMessageRequest(message_req) => {
-                        println!("New message request being sent to individual users. This is prohibited. Aborting client sender thread. Message attempting to be sent: {:?}", message_req);
-                        break;
-                    }
-                };
-
-                match send_message(sender_cloned_ref.clone(), message_to_send_to_client).await {
-                    Ok(_) => {
-                        // If broadcast message is a new message then persist the message delivered time to the database,
-                        // And send it back to the chat room that x user got his message delivered.
-                        if let BroadcastMessage::NewMessage(message) = msg {
-                            let time_delivered = Utc::now();
-                            if !cloned_state.does_message_have_updates_in_queue(&message.id) {
-                                // Add message delivered update to queue if it's not already in a queue (basically lock the queue from writes)
-                                cloned_state.add_message_update_to_queue(
-                                    &message.id,
-                                    ChatMessageUpdate::Delivered(cloned_user_id, time_delivered),
-                                );
-                                // Update data
-                                // NOTE: This part of the code is programmed this way to avoid breaking (Since we're in a while loop, breaking would mean the user would stop recieving messages...)
-                                let persisted_message_opt = match message_dao::get_message(
-                                    &cloned_state.db_conn,
-                                    &message.id,
-                                )
-                                .await
-                                {
-                                    Ok(persisted_message_opt) => persisted_message_opt,
-                                    Err(error) => {
-                                        println!("Something went wrong in the database while performing a get to the message table. Error: {}", error);
-                                        None
-                                    }
-                                };
-                                if persisted_message_opt.is_some() {
-                                    let mut persisted_message = persisted_message_opt.unwrap();
-                                    persisted_message
-                                        .time_delivered
-                                        .list
-                                        .push(TimeSensitiveAction::new(cloned_user_id));
-                                    match message_dao::update_message(&cloned_state.db_conn, &persisted_message).await {
-                                        Ok(_) => {
-                                            // Broadcast the delivered message to all connected sockets, 
-                                            // The idea is that the clients get the same chatmessage,
-                                            // Since they already have that MessageId stored, they can handle it as an update
-                                            match user_send_message(cloned_state.clone(), cloned_user_id, BroadcastMessage::DeliveredUpdate(persisted_message)).await {
-                                                Ok(_) => {},
-                                                Err(error) => println!("Error sending a chat message update to a client: {error}"),
-                                            };
-                                        },
-                                        Err(error) => println!("Something went wrong in the database while performing an update to the message table. Error: {error}"),
-                                    };
-                                    match cloned_state.remove_first_message_update_from_queue(&message.id) {
-                                        Some(_) => {},
-                                        None => println!("Error while removing the first message from the message update queue. This should never happen. MessageId: {}", message.id),
-                                    };
-                                }
-                            } else {
-                                // Message has updates in queue before this one.
-                                // Add it to queue
-                                // Wait until this update is first in the queue then execute.
-                                let message_update =
-                                    ChatMessageUpdate::Delivered(cloned_user_id, time_delivered);
-                                cloned_state.add_message_update_to_queue(
-                                    &message.id,
-                                    message_update.clone(),
-                                );
-                                while !cloned_state
-                                    .is_update_first_in_queue(&message.id, &message_update)
-                                {
-                                    // Wait 70ms
-                                    sleep(Duration::from_millis(50)).await;
-                                }
-                                // After it has been confirmed that the update is first in queue, update db
-                                let persisted_message_opt = match message_dao::get_message(
-                                    &cloned_state.db_conn,
-                                    &message.id,
-                                )
-                                .await
-                                {
-                                    Ok(persisted_message_opt) => persisted_message_opt,
-                                    Err(error) => {
-                                        println!("Something went wrong in the database while performing a get to the message table. Error: {}", error);
-                                        None
-                                    }
-                                };
-                                if persisted_message_opt.is_some() {
-                                    let mut persisted_message = persisted_message_opt.unwrap();
-                                    persisted_message
-                                        .time_delivered
-                                        .list
-                                        .push(TimeSensitiveAction::new(cloned_user_id));
-                                    match message_dao::update_message(&cloned_state.db_conn, &persisted_message).await {
-                                        Ok(_) => {
-                                            // Broadcast the delivered message to all connected sockets, 
-                                            // The idea is that the clients get the same chatmessage,
-                                            // Since they already have that MessageId stored, they can handle it as an update
-                                            match user_send_message(cloned_state.clone(), cloned_user_id, BroadcastMessage::DeliveredUpdate(persisted_message)).await {
-                                                Ok(_) => {},
-                                                Err(error) => println!("Error sending a chat message update to a client: {error}"),
-                                            };
-                                        },
-                                        Err(error) => println!("Something went wrong in the database while performing an update to the message table. Error: {error}"),
-                                    };
-                                    match cloned_state.remove_first_message_update_from_queue(&message.id) {
-                                        Some(_) => {},
-                                        None => println!("Error while removing the first message from the message update queue. This should never happen. MessageId: {}", message.id),
-                                    };
-                                }
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        println!("{error}");
-                        break;
-                    }
-                };
+        match state.add_chat_room_channel(chat_room_id, &user_id) {
+            Ok(channel_reciever_handle) => {
+                subscriptions.insert(chat_room_id, BroadcastStream::new(channel_reciever_handle));
+            }
+            Err(error) => {
+                unregister_addr(&state, addr, &user_id);
+                return Err(error);
             }
-        });
-        all_send_tasks.push(sender_task);
+        }
     }
+    let _ = send_reply(sender, ServerMessageOut::LoggedIn, request_id).await;
     Ok(())
 }
+
+/// Undoes a registration that failed halfway. Whatever wasn't stored yet is skipped.
+fn unregister_addr(state: &AppState, addr: &SocketAddr, user_id: &u32) {
+    state.remove_session(addr);
+    let _ = state.remove_connected_client(addr);
+    let _ = state.remove_user_from_all_groups(user_id);
+}
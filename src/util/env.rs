@@ -1 +1,46 @@
+use std::{env, fmt::Display, str::FromStr};
 
+/// If the env var is set, parses it and overwrites the value with it. The error says which env var is wrong.
+pub fn override_from_env<T: FromStr>(key: &str, value: &mut T) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    match env::var(key) {
+        Ok(raw) => match raw.parse() {
+            Ok(parsed) => {
+                *value = parsed;
+                Ok(())
+            }
+            Err(error) => Err(format!("{key} env var has an invalid value {raw:?}: {error}")),
+        },
+        Err(_) => Ok(()),
+    }
+}
+
+/// Same as `override_from_env` for optional values, an empty env var unsets the value.
+pub fn override_option_from_env(key: &str, value: &mut Option<String>) -> Result<(), String> {
+    if let Ok(raw) = env::var(key) {
+        *value = Some(raw).filter(|raw| !raw.is_empty());
+    }
+    Ok(())
+}
+
+/// Same as `override_from_env` for lists, the env var is comma separated.
+pub fn override_list_from_env<T: FromStr>(key: &str, values: &mut Vec<T>) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Ok(raw) = env::var(key) {
+        *values = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|error| format!("{key} env var has an invalid value {value:?}: {error}"))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(())
+}
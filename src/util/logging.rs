@@ -0,0 +1,14 @@
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogFormat, LoggingConfig};
+
+/// Installs the global subscriber. Everything logged before this (config errors) goes straight to stderr.
+pub fn init(config: &LoggingConfig) {
+    // The config was validated already, the fallback is only there so logging can't take the server down.
+    let filter = EnvFilter::try_new(&config.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
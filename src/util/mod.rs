@@ -1 +1,5 @@
 pub mod env;
+pub mod logging;
+pub mod rate_limit;
+#[cfg(test)]
+pub mod test_util;
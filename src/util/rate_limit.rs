@@ -0,0 +1,213 @@
+use std::{fmt::Display, hash::Hash, str::FromStr, time::Instant};
+
+use dashmap::DashMap;
+use serde::Deserialize;
+
+/// How fast something can be done: `per_second` on average, with up to `burst` at once. Written as
+/// `"<per_second>/<burst>"` in the config file and in env vars, e.g. `"5/10"`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub const fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(rate_limit: &str) -> Result<Self, Self::Err> {
+        let (per_second, burst) = rate_limit
+            .split_once('/')
+            .ok_or_else(|| format!("rate limit {rate_limit:?} must look like <per_second>/<burst>"))?;
+        let per_second: f64 = per_second
+            .trim()
+            .parse()
+            .map_err(|error| format!("invalid per second rate in {rate_limit:?}: {error}"))?;
+        let burst: u32 = burst
+            .trim()
+            .parse()
+            .map_err(|error| format!("invalid burst in {rate_limit:?}: {error}"))?;
+        if !per_second.is_finite() || per_second <= 0.0 || burst == 0 {
+            return Err(format!("rate limit {rate_limit:?} must allow at least something"));
+        }
+        Ok(Self { per_second, burst })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(rate_limit: String) -> Result<Self, Self::Error> {
+        rate_limit.parse()
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.per_second, self.burst)
+    }
+}
+
+/// A token bucket per key. Buckets start full with `burst` tokens, every action takes one and they refill at
+/// `per_second`.
+#[derive(Debug)]
+pub struct RateLimiter<K: Eq + Hash> {
+    limit: RateLimit,
+    buckets: DashMap<K, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * limit.per_second;
+        self.tokens = (self.tokens + refilled).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes a token from the key's bucket. False if it's empty, the action should be refused.
+    pub fn check(&self, key: K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    /// Whether the key's bucket has a token, without taking it. For actions that need a token from more than
+    /// one bucket, so none gets taken unless all of them have one.
+    pub fn has_token(&self, key: &K) -> bool {
+        self.has_token_at(key, Instant::now())
+    }
+
+    /// Drops the buckets that have refilled completely, they're the same as a new one.
+    pub fn prune(&self) {
+        self.prune_at(Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> bool {
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: self.limit.burst as f64,
+            last_refill: now,
+        });
+        bucket.refill(&self.limit, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn has_token_at(&self, key: &K, now: Instant) -> bool {
+        match self.buckets.get_mut(key) {
+            Some(mut bucket) => {
+                bucket.refill(&self.limit, now);
+                bucket.tokens >= 1.0
+            }
+            None => true,
+        }
+    }
+
+    fn prune_at(&self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(&self.limit, now);
+            bucket.tokens < self.limit.burst as f64
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, RateLimiter};
+
+    #[test]
+    fn parses_per_second_and_burst() {
+        assert_eq!("5/10".parse(), Ok(RateLimit::new(5.0, 10)));
+        assert_eq!(" 0.5 / 3 ".parse(), Ok(RateLimit::new(0.5, 3)));
+        assert_eq!(RateLimit::new(0.5, 3).to_string().parse(), Ok(RateLimit::new(0.5, 3)));
+    }
+
+    #[test]
+    fn rejects_junk() {
+        for rate_limit in ["", "5", "5/", "/10", "five/10", "5/ten", "5/10/15", "5/-1", "5/1.5"] {
+            assert!(rate_limit.parse::<RateLimit>().is_err(), "{rate_limit:?} parsed");
+        }
+    }
+
+    #[test]
+    fn rejects_limits_that_allow_nothing() {
+        for rate_limit in ["0/10", "5/0", "-1/10", "inf/10", "NaN/10"] {
+            assert!(rate_limit.parse::<RateLimit>().is_err(), "{rate_limit:?} parsed");
+        }
+    }
+
+    #[test]
+    fn refuses_once_the_burst_is_used_up() {
+        let limiter = RateLimiter::new(RateLimit::new(1.0, 3));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at(1, now));
+        }
+        assert!(!limiter.check_at(1, now));
+        assert!(!limiter.has_token_at(&1, now));
+        // Every key has a bucket of its own.
+        assert!(limiter.check_at(2, now));
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let limiter = RateLimiter::new(RateLimit::new(2.0, 2));
+        let start = Instant::now();
+        assert!(limiter.check_at(1, start));
+        assert!(limiter.check_at(1, start));
+        assert!(!limiter.check_at(1, start));
+        assert!(limiter.check_at(1, start + Duration::from_millis(500)));
+        assert!(!limiter.check_at(1, start + Duration::from_millis(500)));
+        // A long break doesn't refill more than the burst.
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check_at(1, later));
+        assert!(limiter.check_at(1, later));
+        assert!(!limiter.check_at(1, later));
+    }
+
+    #[test]
+    fn has_token_doesnt_take_it() {
+        let limiter = RateLimiter::new(RateLimit::new(1.0, 1));
+        let now = Instant::now();
+        assert!(limiter.has_token_at(&1, now));
+        assert!(limiter.has_token_at(&1, now));
+        assert!(limiter.check_at(1, now));
+        assert!(!limiter.has_token_at(&1, now));
+    }
+
+    #[test]
+    fn prune_only_drops_full_buckets() {
+        let limiter = RateLimiter::new(RateLimit::new(1.0, 2));
+        let start = Instant::now();
+        limiter.check_at(1, start);
+        limiter.check_at(1, start);
+        limiter.check_at(2, start);
+        limiter.prune_at(start + Duration::from_secs(1));
+        assert!(limiter.buckets.contains_key(&1));
+        assert!(!limiter.buckets.contains_key(&2));
+        limiter.prune_at(start + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+    }
+}
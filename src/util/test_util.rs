@@ -0,0 +1,190 @@
+//! Helpers for the in-process tests: an AppState on top of the `#[sqlx::test]` database with mock auth and local
+//! fanout, requests straight into the router without a listening socket, and real sockets for the tests that
+//! need frames.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use futures::StreamExt;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use serde_json::Value;
+use sqlx::MySqlPool;
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::CloseFrame, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tower::ServiceExt;
+
+use crate::{
+    config::Config,
+    domain::state::AppState,
+    routes::http::main_router,
+    service::{auth::mock::MockAuthenticator, fanout::local::LocalFanout},
+};
+
+/// For tests that don't touch the database. Nothing listens on its address, so anything that does fails.
+pub fn lazy_pool() -> MySqlPool {
+    MySqlPool::connect_lazy("mysql://127.0.0.1:1/unused").unwrap()
+}
+
+/// The default config with whatever the test changes on top.
+pub fn test_state(pool: MySqlPool, configure: impl FnOnce(&mut Config)) -> Arc<AppState> {
+    let mut config = Config::default();
+    configure(&mut config);
+    Arc::new(AppState::new(
+        pool,
+        reqwest::Client::new(),
+        Arc::new(MockAuthenticator),
+        Arc::new(LocalFanout),
+        &config,
+    ))
+}
+
+pub fn test_router(state: Arc<AppState>) -> Router {
+    main_router::router(state, PrometheusBuilder::new().build_recorder().handle())
+}
+
+/// The recorder metrics macros write to. Only tests install one, and it's shared by every test in the run, so tests
+/// should check by how much a counter went up rather than its value.
+fn installed_recorder() -> &'static PrometheusHandle {
+    static RECORDER: OnceLock<PrometheusHandle> = OnceLock::new();
+    RECORDER.get_or_init(|| PrometheusBuilder::new().install_recorder().unwrap())
+}
+
+/// The counter's current value, 0 if it was never incremented.
+pub fn counter_value(name: &str, labels: &[(&str, &str)]) -> u64 {
+    let labels: Vec<String> = labels.iter().map(|(key, value)| format!("{key}=\"{value}\"")).collect();
+    let series = match labels.is_empty() {
+        true => format!("{name} "),
+        false => format!("{name}{{{}}} ", labels.join(",")),
+    };
+    installed_recorder()
+        .render()
+        .lines()
+        .find_map(|line| line.strip_prefix(series.as_str()))
+        .map_or(0, |value| value.trim().parse().unwrap())
+}
+
+/// Sends the request as `user_id` with a mock token. The body is parsed as JSON, Null if it's empty.
+pub async fn request_as(router: &Router, user_id: u32, method: Method, path: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::AUTHORIZATION, format!("Bearer {user_id}:mock-{user_id}"));
+    let request = match body {
+        Some(body) => request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    };
+    send(router, request.unwrap()).await
+}
+
+pub async fn send(router: &Router, request: Request<Body>) -> (StatusCode, Value) {
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = match body.is_empty() {
+        true => Value::Null,
+        false => serde_json::from_slice(&body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned())),
+    };
+    (status, body)
+}
+
+/// Creates a room owned by `owner_id` with `member_ids` in it through the API and returns its id.
+pub async fn create_room(router: &Router, owner_id: u32, member_ids: &[u32]) -> u32 {
+    let (status, chat_room) = request_as(
+        router,
+        owner_id,
+        Method::POST,
+        "/chat/room/test-room",
+        Some(serde_json::json!({ "participants": member_ids })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{chat_room}");
+    chat_room["id"].as_u64().expect("chat room without an id") as u32
+}
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Serves the router on a random local port for as long as the test runs.
+pub fn serve(state: Arc<AppState>) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(test_router(state).into_make_service_with_connect_info::<SocketAddr>());
+    tokio::spawn(server);
+    addr
+}
+
+/// Logs in during the handshake and waits until the session is registered, so session events reach the socket.
+pub async fn connect_as(state: &AppState, addr: SocketAddr, user_id: u32) -> Socket {
+    let (mut socket, _) = connect_async(format!("ws://{addr}/websocket?user_id={user_id}&token=mock-{user_id}"))
+        .await
+        .unwrap();
+    assert_eq!(next_frame(&mut socket).await["head"], "LOGGED IN");
+    timeout(Duration::from_secs(5), async {
+        while !state.sessions.iter().any(|session| session.user_id == user_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Timed out waiting for the session");
+    socket
+}
+
+/// Opens a socket without credentials, it has to log in through a frame.
+pub async fn connect_anonymously(addr: SocketAddr) -> Socket {
+    let (socket, _) = connect_async(format!("ws://{addr}/websocket")).await.unwrap();
+    socket
+}
+
+/// Reads until the server closes the socket and returns the close frame it sent, skipping everything else.
+pub async fn next_close(socket: &mut Socket) -> CloseFrame<'static> {
+    loop {
+        let message = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for the socket to close")
+            .expect("Socket ended without a close frame")
+            .expect("Socket error");
+        if let Message::Close(close_frame) = message {
+            return close_frame.expect("Close frame without a code");
+        }
+    }
+}
+
+/// Skips pings and anything that isn't JSON.
+pub async fn next_frame(socket: &mut Socket) -> Value {
+    loop {
+        let message = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for a frame")
+            .expect("Socket closed")
+            .expect("Socket error");
+        if let Message::Text(text) = message {
+            if let Ok(frame) = serde_json::from_str(&text) {
+                return frame;
+            }
+        }
+    }
+}
+
+pub async fn next_frame_with_head(socket: &mut Socket, head: &str) -> Value {
+    loop {
+        let frame = next_frame(socket).await;
+        if frame["head"] == head {
+            return frame;
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Helpers shared by the integration tests, which all talk to a running server started with `AUTH_MODE=mock`.
+#![allow(unused)]
+
+use rand::Rng;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+
+pub fn base_url() -> String {
+    std::env::var("CHAT_BACKEND_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+/// Random ids so runs don't step on each other's rooms.
+pub fn random_user_id() -> u32 {
+    rand::thread_rng().gen_range(1_000_000..u32::MAX)
+}
+
+pub async fn request_as(user_id: u32, method: Method, path: &str, body: Option<Value>) -> reqwest::Response {
+    let mut request = reqwest::Client::new()
+        .request(method, format!("{}{path}", base_url()))
+        .bearer_auth(format!("{user_id}:mock-{user_id}"));
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    request.send().await.expect("Couldn't reach the server, is it running?")
+}
+
+pub struct Room {
+    pub id: u64,
+    pub owner_id: u32,
+    pub member_id: u32,
+}
+
+/// A room with its owner and one other member.
+pub async fn create_room(title: &str) -> Room {
+    let owner_id = random_user_id();
+    let member_id = random_user_id();
+    let response = request_as(
+        owner_id,
+        Method::POST,
+        &format!("/chat/room/{title}"),
+        Some(json!({ "participants": [member_id] })),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let chat_room: Value = response.json().await.unwrap();
+    Room {
+        id: chat_room["id"].as_u64().expect("chat room without an id"),
+        owner_id,
+        member_id,
+    }
+}
+
+pub async fn assert_forbidden(response: reqwest::Response) {
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(error["code"], "NOT_A_MEMBER");
+}
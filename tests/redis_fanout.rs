@@ -0,0 +1,99 @@
+//! Checks that messages and kicks sent through one instance reach sockets connected to another one, with both
+//! instances publishing through the same Redis.
+//!
+//! Start a Redis and two instances with mock auth on different ports, then point CHAT_BACKEND_URL and
+//! CHAT_BACKEND_URL_B at them:
+//!
+//! ```sh
+//! docker run --rm -p 6379:6379 redis:7
+//! AUTH_MODE=mock FANOUT_MODE=redis REDIS_URL=redis://localhost:6379 cargo run -- serve --migrate
+//! AUTH_MODE=mock FANOUT_MODE=redis REDIS_URL=redis://localhost:6379 LISTEN_ADDR=0.0.0.0:3001 cargo run
+//! CHAT_BACKEND_URL_B=http://localhost:3001 cargo test --test redis_fanout -- --ignored
+//! ```
+
+mod common;
+
+use std::time::Duration;
+
+use common::{base_url, create_room, request_as};
+use futures::{SinkExt, StreamExt};
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use tokio::{net::TcpStream, time::timeout};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+fn second_base_url() -> String {
+    std::env::var("CHAT_BACKEND_URL_B").unwrap_or_else(|_| "http://localhost:3001".to_string())
+}
+
+/// Logs in during the handshake and waits for the LOGGED IN frame, so the socket is subscribed to its rooms.
+async fn connect_as(base_url: &str, user_id: u32) -> Socket {
+    let url = format!(
+        "{}/websocket?user_id={user_id}&token=mock-{user_id}",
+        base_url.replacen("http", "ws", 1)
+    );
+    let (mut socket, _) = connect_async(url).await.expect("Couldn't open a socket, is the server running?");
+    let frame = next_frame(&mut socket).await;
+    assert_eq!(frame["head"], "LOGGED IN");
+    socket
+}
+
+/// Skips pings and anything that isn't JSON.
+async fn next_frame(socket: &mut Socket) -> Value {
+    loop {
+        let message = timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("Timed out waiting for a frame")
+            .expect("Socket closed")
+            .expect("Socket error");
+        if let Message::Text(text) = message {
+            if let Ok(frame) = serde_json::from_str(&text) {
+                return frame;
+            }
+        }
+    }
+}
+
+async fn next_frame_with_head(socket: &mut Socket, head: &str) -> Value {
+    loop {
+        let frame = next_frame(socket).await;
+        if frame["head"] == head {
+            return frame;
+        }
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Redis and two running servers with AUTH_MODE=mock and FANOUT_MODE=redis"]
+async fn messages_reach_members_connected_to_another_instance() {
+    let room = create_room("fanout-test").await;
+    let mut sender = connect_as(&base_url(), room.owner_id).await;
+    let mut recipient = connect_as(&second_base_url(), room.member_id).await;
+
+    let text = format!("hello from {}", room.owner_id);
+    let frame = json!({ "head": "SEND MESSAGE", "body": { "to": room.id, "message": { "Text": text } } });
+    sender.send(Message::Text(frame.to_string())).await.unwrap();
+
+    let received = next_frame_with_head(&mut recipient, "MESSAGE RECIEVED").await;
+    assert_eq!(received["body"]["toId"], room.id);
+    assert_eq!(received["body"]["message"]["Text"], text);
+    // The sender's own instance gets it back through Redis too.
+    let echoed = next_frame_with_head(&mut sender, "MESSAGE RECIEVED").await;
+    assert_eq!(echoed["body"]["id"], received["body"]["id"]);
+}
+
+#[tokio::test]
+#[ignore = "needs Redis and two running servers with AUTH_MODE=mock and FANOUT_MODE=redis"]
+async fn kicks_reach_members_connected_to_another_instance() {
+    let room = create_room("fanout-test").await;
+    let mut kicked = connect_as(&second_base_url(), room.member_id).await;
+
+    let response = request_as(room.owner_id, Method::DELETE, &format!("/chat/room/{}/kick/{}", room.id, room.member_id), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let frame = next_frame_with_head(&mut kicked, "KICKED").await;
+    assert_eq!(frame["body"]["chatRoomId"], room.id);
+    assert_eq!(frame["body"]["userId"], room.member_id);
+}